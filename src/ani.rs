@@ -0,0 +1,197 @@
+use std::{
+    convert::TryInto,
+    time::Duration
+};
+
+use crate::{
+    bytes::u32_at,
+    exelook::{self, Diagnostic, Error, Icon, Options, Result}
+};
+
+pub const AF_ICON: u32 = 1;
+pub const AF_SEQUENCE: u32 = 2;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AniHeader {
+    pub frames: u32,
+    pub steps: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bit_count: u32,
+    pub planes: u32,
+    // Default step length in jiffies (1/60 s) when there is no rate chunk.
+    pub display_rate: u32,
+    pub flags: u32
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AniFile<'a> {
+    pub header: AniHeader,
+    pub rates: Vec<u32>,
+    pub sequence: Vec<u32>,
+    // Every frame is a complete .ico/.cur file.
+    pub frames: Vec<&'a [u8]>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AniStep {
+    pub frame: usize,
+    pub jiffies: u32
+}
+
+impl AniStep {
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.jiffies as u64 * 1000 / 60)
+    }
+}
+
+// steps index into frames. Frames that fail to decode are left out, along with
+// the steps showing them, and reported in diagnostics by their position in the
+// file.
+#[derive(Debug)]
+pub struct AnimatedCursor {
+    pub header: AniHeader,
+    pub frames: Vec<Icon>,
+    pub steps: Vec<AniStep>,
+    pub diagnostics: Vec<Diagnostic>
+}
+
+impl AnimatedCursor {
+    // The frame shown first, which is what a thumbnail should use.
+    pub fn into_first_frame(mut self) -> Result<Icon> {
+        let index = self.steps.first().map_or(0, |step| step.frame);
+        if index >= self.frames.len() {
+            return Err(self.diagnostics.into_iter().next().map_or(Error::NoIconFound, |diag| diag.error));
+        }
+        let mut frame = self.frames.swap_remove(index);
+        frame.diagnostics.append(&mut self.diagnostics);
+        Ok(frame)
+    }
+}
+
+fn u32_list(bytes: &[u8]) -> Vec<u32> {
+    bytes.chunks_exact(4).map(|value| u32::from_le_bytes(value.try_into().unwrap())).collect()
+}
+
+// RIFF chunks are padded to an even size; a missing pad byte at the very end is
+// tolerated, a chunk running past its parent is not.
+fn chunks(mut bytes: &[u8]) -> Result<Vec<(&[u8], &[u8])>> {
+    let mut chunks = Vec::new();
+    while bytes.len() >= 8 {
+        let size = u32_at(bytes, 4, Error::MalformedAni)? as usize;
+        let end = size.checked_add(8).ok_or(Error::MalformedAni)?;
+        let data = bytes.get(8..end).ok_or(Error::MalformedAni)?;
+        chunks.push((&bytes[..4], data));
+        bytes = bytes.get(end + (size & 1)..).unwrap_or(&[]);
+    }
+    Ok(chunks)
+}
+
+pub fn is_ani(bytes: &[u8]) -> bool {
+    bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"ACON"
+}
+
+pub fn parse_ani(bytes: &[u8]) -> Result<AniFile<'_>> {
+    if !is_ani(bytes) {
+        return Err(Error::MalformedAni);
+    }
+    let riff_end = (u32_at(bytes, 4, Error::MalformedAni)? as usize).saturating_add(8).min(bytes.len());
+    let mut header = None;
+    let mut rates = Vec::new();
+    let mut sequence = Vec::new();
+    let mut frames = Vec::new();
+    for (id, data) in chunks(&bytes[12..riff_end])? {
+        match id {
+            b"anih" => {
+                header = Some(AniHeader {
+                    frames: u32_at(data, 4, Error::MalformedAni)?,
+                    steps: u32_at(data, 8, Error::MalformedAni)?,
+                    width: u32_at(data, 12, Error::MalformedAni)?,
+                    height: u32_at(data, 16, Error::MalformedAni)?,
+                    bit_count: u32_at(data, 20, Error::MalformedAni)?,
+                    planes: u32_at(data, 24, Error::MalformedAni)?,
+                    display_rate: u32_at(data, 28, Error::MalformedAni)?,
+                    flags: u32_at(data, 32, Error::MalformedAni)?
+                });
+            },
+            b"rate" => rates = u32_list(data),
+            b"seq " => sequence = u32_list(data),
+            b"LIST" if data.starts_with(b"fram") => {
+                for (id, frame) in chunks(&data[4..])? {
+                    if id == b"icon" {
+                        frames.push(frame);
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+    let header = header.ok_or(Error::MalformedAni)?;
+    Ok(AniFile {header, rates, sequence, frames})
+}
+
+// Frame indices of the steps, in playing order.
+fn step_frames(ani: &AniFile) -> Vec<usize> {
+    let step_count = if ani.sequence.is_empty() {ani.frames.len()} else {ani.sequence.len()};
+    let step_count = if ani.header.steps == 0 {step_count} else {step_count.min(ani.header.steps as usize)};
+    (0..step_count).map(|step| if ani.sequence.is_empty() {step} else {ani.sequence[step] as usize}).collect()
+}
+
+pub fn decode_ani(bytes: &[u8], options: &Options) -> Result<AnimatedCursor> {
+    let ani = parse_ani(bytes)?;
+    if ani.header.flags & AF_ICON == 0 {
+        return Err(Error::UnsupportedAni);
+    }
+    let mut frames = Vec::new();
+    let mut positions = Vec::new();
+    let mut diagnostics = Vec::new();
+    // The limits apply to every frame, and to all of them together.
+    let mut total = 0u64;
+    for (index, frame) in ani.frames.iter().enumerate() {
+        match exelook::read_icon_file(frame, options) {
+            Ok(icon) => {
+                total += icon.image.pixels.len() as u64;
+                options.limits.check_bytes(total)?;
+                positions.push(Some(frames.len()));
+                frames.push(icon);
+            },
+            Err(error) => {
+                positions.push(None);
+                diagnostics.push(Diagnostic {group: None, entry: Some(index as u16), error});
+            }
+        }
+    }
+    let steps = step_frames(&ani).into_iter().enumerate().filter_map(|(step, frame)| {
+        let jiffies = ani.rates.get(step).cloned().unwrap_or(ani.header.display_rate);
+        Some(AniStep {frame: (*positions.get(frame)?)?, jiffies})
+    }).collect();
+    Ok(AnimatedCursor {header: ani.header, frames, steps, diagnostics})
+}
+
+// What AnimatedCursor::into_first_frame returns, without decoding the frames a
+// thumbnail never shows: only the first step's frame, or the next one that
+// decodes.
+pub fn decode_first_frame(bytes: &[u8], options: &Options) -> Result<Icon> {
+    let ani = parse_ani(bytes)?;
+    if ani.header.flags & AF_ICON == 0 {
+        return Err(Error::UnsupportedAni);
+    }
+    let mut diagnostics = Vec::new();
+    let mut tried = Vec::new();
+    for index in step_frames(&ani) {
+        let frame = match ani.frames.get(index) {
+            Some(frame) if !tried.contains(&index) => frame,
+            _ => continue
+        };
+        tried.push(index);
+        match exelook::read_icon_file(frame, options) {
+            Ok(mut icon) => {
+                diagnostics.append(&mut icon.diagnostics);
+                icon.diagnostics = diagnostics;
+                return Ok(icon);
+            },
+            Err(error) => diagnostics.push(Diagnostic {group: None, entry: Some(index as u16), error})
+        }
+    }
+    Err(diagnostics.into_iter().next().map_or(Error::NoIconFound, |diag| diag.error))
+}
@@ -0,0 +1,19 @@
+use std::convert::TryInto;
+
+use crate::exelook::{Error, Result};
+
+// Little-endian fields of the binary formats; reading past the end fails with
+// the format's own error.
+pub fn u16_at(bytes: &[u8], offset: usize, error: Error) -> Result<u16> {
+    match offset.checked_add(2).and_then(|end| bytes.get(offset..end)) {
+        Some(field) => Ok(u16::from_le_bytes(field.try_into().unwrap())),
+        None => Err(error)
+    }
+}
+
+pub fn u32_at(bytes: &[u8], offset: usize, error: Error) -> Result<u32> {
+    match offset.checked_add(4).and_then(|end| bytes.get(offset..end)) {
+        Some(field) => Ok(u32::from_le_bytes(field.try_into().unwrap())),
+        None => Err(error)
+    }
+}
@@ -0,0 +1,206 @@
+use std::convert::TryInto;
+
+use crate::{
+    bytes::{u16_at, u32_at},
+    exelook::{Error, Limits, Result}
+};
+
+const MAGIC: &[u8] = b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1";
+const HEADER_SIZE: usize = 512;
+const HEADER_DIFAT: usize = 109;
+const DIR_ENTRY_SIZE: usize = 128;
+// Sector chain markers
+const MAX_SECTOR: u32 = 0xffff_fffa;
+const NO_STREAM: u32 = 0xffff_ffff;
+
+pub const STGTY_STORAGE: u8 = 1;
+pub const STGTY_STREAM: u8 = 2;
+pub const STGTY_ROOT: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    // UTF-16 without the terminator; MSI packs other data into these.
+    pub name: Vec<u16>,
+    pub kind: u8,
+    left: u32,
+    right: u32,
+    child: u32,
+    start: u32,
+    pub size: u64
+}
+
+// A read-only OLE compound document (structured storage), version 3 or 4.
+pub struct CompoundFile<'a> {
+    bytes: &'a [u8],
+    sector_shift: u32,
+    mini_sector_shift: u32,
+    mini_cutoff: u64,
+    fat: Vec<u32>,
+    mini_fat: Vec<u32>,
+    mini_stream: Vec<u8>,
+    pub entries: Vec<DirEntry>
+}
+
+fn u32_list(bytes: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    bytes.chunks_exact(4).map(|value| u32::from_le_bytes(value.try_into().unwrap()))
+}
+
+pub fn is_compound_file(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Follows a FAT or mini FAT chain. A sector appearing twice means a loop.
+fn chain(table: &[u32], start: u32) -> Result<Vec<u32>> {
+    let mut sectors = Vec::new();
+    let mut visited = vec![false; table.len()];
+    let mut sector = start;
+    while sector <= MAX_SECTOR {
+        let next = *table.get(sector as usize).ok_or(Error::MalformedCompoundFile)?;
+        if std::mem::replace(&mut visited[sector as usize], true) {
+            return Err(Error::MalformedCompoundFile);
+        }
+        sectors.push(sector);
+        sector = next;
+    }
+    Ok(sectors)
+}
+
+impl<'a> CompoundFile<'a> {
+    fn sector(&self, sector: u32) -> Result<&'a [u8]> {
+        let size = 1usize << self.sector_shift;
+        let start = (sector as usize + 1).checked_mul(size).ok_or(Error::MalformedCompoundFile)?;
+        // The last sector may be cut short.
+        self.bytes.get(start..start.saturating_add(size).min(self.bytes.len())).filter(|data| !data.is_empty()).ok_or(Error::MalformedCompoundFile)
+    }
+
+    fn read_chain(&self, start: u32, size: u64, limits: &Limits) -> Result<Vec<u8>> {
+        limits.check_bytes(size)?;
+        let mut data = Vec::with_capacity(size as usize);
+        for sector in chain(&self.fat, start)? {
+            if data.len() as u64 >= size {
+                break;
+            }
+            data.extend_from_slice(self.sector(sector)?);
+        }
+        if (data.len() as u64) < size {
+            return Err(Error::MalformedCompoundFile);
+        }
+        data.truncate(size as usize);
+        Ok(data)
+    }
+
+    pub fn parse(bytes: &'a [u8], limits: &Limits) -> Result<CompoundFile<'a>> {
+        if !is_compound_file(bytes) || bytes.len() < HEADER_SIZE {
+            return Err(Error::MalformedCompoundFile);
+        }
+        let sector_shift = u16_at(bytes, 0x1e, Error::MalformedCompoundFile)? as u32;
+        let mini_sector_shift = u16_at(bytes, 0x20, Error::MalformedCompoundFile)? as u32;
+        if !(sector_shift == 9 || sector_shift == 12) || mini_sector_shift >= sector_shift {
+            return Err(Error::MalformedCompoundFile);
+        }
+        let mut file = CompoundFile {
+            bytes,
+            sector_shift,
+            mini_sector_shift,
+            mini_cutoff: u32_at(bytes, 0x38, Error::MalformedCompoundFile)? as u64,
+            fat: Vec::new(),
+            mini_fat: Vec::new(),
+            mini_stream: Vec::new(),
+            entries: Vec::new()
+        };
+        // The FAT sectors are listed in the header and then in a chain of DIFAT
+        // sectors, each ending with the number of the next one.
+        let fat_count = u32_at(bytes, 0x2c, Error::MalformedCompoundFile)? as usize;
+        let mut fat_sectors: Vec<u32> = u32_list(&bytes[0x4c..0x4c + HEADER_DIFAT * 4]).collect();
+        let mut difat = u32_at(bytes, 0x44, Error::MalformedCompoundFile)?;
+        let mut difat_count = 0;
+        while difat <= MAX_SECTOR && fat_sectors.len() < fat_count {
+            difat_count += 1;
+            if difat_count > bytes.len() >> sector_shift {
+                return Err(Error::MalformedCompoundFile);
+            }
+            let sector = file.sector(difat)?;
+            let (entries, next) = sector.split_at(sector.len().checked_sub(4).ok_or(Error::MalformedCompoundFile)?);
+            fat_sectors.extend(u32_list(entries));
+            difat = u32_at(next, 0, Error::MalformedCompoundFile)?;
+        }
+        if fat_sectors.len() < fat_count || fat_count > bytes.len() >> sector_shift {
+            return Err(Error::MalformedCompoundFile);
+        }
+        for &sector in &fat_sectors[..fat_count] {
+            file.fat.extend(u32_list(file.sector(sector)?));
+        }
+        let dir_start = u32_at(bytes, 0x30, Error::MalformedCompoundFile)?;
+        let directory = chain(&file.fat, dir_start)?.into_iter().map(|sector| file.sector(sector)).collect::<Result<Vec<_>>>()?;
+        for entry in directory.iter().flat_map(|sector| sector.chunks_exact(DIR_ENTRY_SIZE)) {
+            let name_len = (u16_at(entry, 64, Error::MalformedCompoundFile)? as usize / 2).saturating_sub(1).min(32);
+            file.entries.push(DirEntry {
+                name: (0..name_len).map(|i| u16_at(entry, i * 2, Error::MalformedCompoundFile)).collect::<Result<_>>()?,
+                kind: entry[66],
+                left: u32_at(entry, 68, Error::MalformedCompoundFile)?,
+                right: u32_at(entry, 72, Error::MalformedCompoundFile)?,
+                child: u32_at(entry, 76, Error::MalformedCompoundFile)?,
+                start: u32_at(entry, 116, Error::MalformedCompoundFile)?,
+                // Version 3 files may leave garbage in the high half.
+                size: if sector_shift == 9 {u32_at(entry, 120, Error::MalformedCompoundFile)? as u64} else {u32_at(entry, 120, Error::MalformedCompoundFile)? as u64 | (u32_at(entry, 124, Error::MalformedCompoundFile)? as u64) << 32}
+            });
+        }
+        let root = file.entries.first().filter(|root| root.kind == STGTY_ROOT).ok_or(Error::MalformedCompoundFile)?;
+        let (root_start, root_size) = (root.start, root.size);
+        let mini_fat_start = u32_at(bytes, 0x3c, Error::MalformedCompoundFile)?;
+        if mini_fat_start <= MAX_SECTOR {
+            let sectors = chain(&file.fat, mini_fat_start)?;
+            for sector in sectors {
+                file.mini_fat.extend(u32_list(file.sector(sector)?));
+            }
+            file.mini_stream = file.read_chain(root_start, root_size, limits)?;
+        }
+        Ok(file)
+    }
+
+    // The children of a storage are kept in a red-black tree; the order is
+    // irrelevant here, only that every entry is visited once.
+    pub fn children(&self, storage: usize) -> Result<Vec<&DirEntry>> {
+        let mut children = Vec::new();
+        let mut visited = vec![false; self.entries.len()];
+        let mut pending = vec![self.entries.get(storage).ok_or(Error::MalformedCompoundFile)?.child];
+        while let Some(index) = pending.pop() {
+            if index == NO_STREAM {
+                continue;
+            }
+            let entry = self.entries.get(index as usize).ok_or(Error::MalformedCompoundFile)?;
+            if std::mem::replace(&mut visited[index as usize], true) {
+                return Err(Error::MalformedCompoundFile);
+            }
+            children.push(entry);
+            pending.push(entry.left);
+            pending.push(entry.right);
+        }
+        Ok(children)
+    }
+
+    // Streams below the cutoff live in the mini stream, in 64-byte sectors.
+    pub fn read_stream(&self, entry: &DirEntry, limits: &Limits) -> Result<Vec<u8>> {
+        if entry.kind != STGTY_STREAM {
+            return Err(Error::MalformedCompoundFile);
+        }
+        limits.check_bytes(entry.size)?;
+        if entry.size >= self.mini_cutoff {
+            return self.read_chain(entry.start, entry.size, limits);
+        }
+        let size = 1usize << self.mini_sector_shift;
+        let mut data = Vec::with_capacity(entry.size as usize);
+        for sector in chain(&self.mini_fat, entry.start)? {
+            if data.len() as u64 >= entry.size {
+                break;
+            }
+            let start = (sector as usize).checked_mul(size).ok_or(Error::MalformedCompoundFile)?;
+            data.extend_from_slice(self.mini_stream.get(start..start + size).ok_or(Error::MalformedCompoundFile)?);
+        }
+        if (data.len() as u64) < entry.size {
+            return Err(Error::MalformedCompoundFile);
+        }
+        data.truncate(entry.size as usize);
+        Ok(data)
+    }
+}
@@ -1,13 +1,16 @@
 use std::{
     fmt,
+    borrow::Cow,
+    cell::Cell,
     convert::TryInto
 };
 use pelite::Error::Bounds;
-use crate::exelook::{Result, Error};
+use crate::exelook::{Result, Error, Limits};
 
 
-pub(crate) struct BitmapInfoHeader<'a> {
-    bytes: &'a [u8]
+pub struct BitmapInfoHeader<'a> {
+    bytes: &'a [u8],
+    dib: &'a [u8]
 }
 
 #[derive(Debug)]
@@ -29,79 +32,225 @@ impl Pixel {
 
 pub struct DIB<'a> {
     palette: &'a [u8],
-    xor_mask: &'a [u8],
+    xor_mask: Cow<'a, [u8]>,
     and_mask: &'a [u8],
     row_size: usize,
     mask_row_size: usize,
     real_height: usize,
     width: usize,
     bit_count: u16,
-    upside_down: bool
+    bitfields: bool,
+    masks: [u32; 4],
+    palette_entry_size: usize,
+    upside_down: bool,
+    bad_index: Cell<bool>
 }
 
-impl<'a> DIB<'a> {
-    fn pixel_at_1bpp(&self, x: usize, ry: usize, rym: usize) -> Pixel {
-        let idx = (self.xor_mask[ry + x / 8] >> (7 - (x % 8))) as usize & 1;
-        let blue = self.palette[idx * 4];
-        let green = self.palette[idx * 4 + 1];
-        let red = self.palette[idx * 4 + 2];
-        let alpha = (self.and_mask[rym + x / 8] >> (7 - (x % 8))) & 1;
-        Pixel {red, green, blue, alpha: if alpha == 0 {255} else {0}}
-    }
-    fn pixel_at_4bpp(&self, x: usize, ry: usize, rym: usize) -> Pixel {
-        let idx = (self.xor_mask[ry + x / 2] >> (if x % 2 == 0 {4} else {0})) as usize & 15;
-        let blue = self.palette[idx * 4];
-        let green = self.palette[idx * 4 + 1];
-        let red = self.palette[idx * 4 + 2];
-        let alpha = (self.and_mask[rym + x / 8] >> (7 - (x % 8))) & 1;
-        Pixel {red, green, blue, alpha: if alpha == 0 {255} else {0}}
-    }
-    fn pixel_at_8bpp(&self, x: usize, ry: usize, rym: usize) -> Pixel {
-        let idx = self.xor_mask[ry + x] as usize;
-        let blue = self.palette[idx * 4];
-        let green = self.palette[idx * 4 + 1];
-        let red = self.palette[idx * 4 + 2];
-        let alpha = (self.and_mask[rym + x / 8] >> (7 - (x % 8))) & 1;
-        Pixel {red, green, blue, alpha: if alpha == 0 {255} else {0}}
-    }
-    fn pixel_at_24bpp(&self, x: usize, ry: usize, rym: usize) -> Pixel {
-        let blue = self.xor_mask[ry + x * 3];
-        let green = self.xor_mask[ry + x * 3 + 1];
-        let red = self.xor_mask[ry + x * 3 + 2];
-        let alpha = (self.and_mask[rym + x / 8] >> (7 - (x % 8))) & 1;
-        Pixel {red, green, blue, alpha: if alpha == 0 {255} else {0}}
-    }
-    fn pixel_at_32bpp(&self, x: usize, ry: usize, rym: usize) -> Pixel {
-        let blue = self.xor_mask[ry + x * 4];
-        let green = self.xor_mask[ry + x * 4 + 1];
-        let red = self.xor_mask[ry + x * 4 + 2];
-        let alpha = self.xor_mask[ry + x * 4 + 3];
-        let mask = (self.and_mask[rym + x / 8] >> (7 - (x % 8))) & 1;
-        Pixel {red, green, blue, alpha: if alpha == 0 && mask == 0 {255} else {alpha}}
-    }
-    fn from_bytes<'b>(hdr: &'b BitmapInfoHeader, bytes: &'b [u8]) -> Result<DIB<'b>> {
-        match hdr.bit_count() {
-            1 => {
-                let row_size = hdr.width() as usize / 8 + if hdr.width() % 8 != 0 {1} else {0};
-                DIB::from_bytes_shared(hdr, bytes, 4 * 2, row_size)
-            },
-            4 => {
-                let row_size = (hdr.width() / 2 + hdr.width() % 2) as usize;
-                DIB::from_bytes_shared(hdr, bytes, 4 * 16, row_size)
+const BI_RGB: u32 = 0;
+const BI_RLE8: u32 = 1;
+const BI_RLE4: u32 = 2;
+const BI_BITFIELDS: u32 = 3;
+
+fn out_of_bounds() -> Error {
+    Bounds.into()
+}
+
+// DWORD-aligned size of a row of width pixels, None on overflow
+fn stride(width: usize, bit_count: usize) -> Option<usize> {
+    Some(width.checked_mul(bit_count)?.checked_add(31)? / 32 * 4)
+}
+
+// Expands BI_RLE8/BI_RLE4 data into the same bottom-up, DWORD-aligned rows an
+// uncompressed DIB would have. Pixels the stream skips over stay at index 0.
+fn decode_rle(data: &[u8], rle4: bool, width: usize, height: usize, row_size: usize) -> Result<Vec<u8>> {
+    let mut out = vec![0u8; row_size * height];
+    let mut put = |x: usize, y: usize, idx: u8| -> Result<()> {
+        if x >= width || y >= height {
+            return Err(Error::MalformedRle);
+        }
+        if rle4 {
+            let shift = if x & 1 == 0 {4} else {0};
+            *out.get_mut(y * row_size + x / 2).ok_or(Error::MalformedRle)? |= (idx & 15) << shift;
+        } else {
+            *out.get_mut(y * row_size + x).ok_or(Error::MalformedRle)? = idx;
+        }
+        Ok(())
+    };
+    let nibble = |byte: u8, i: usize| if rle4 {if i & 1 == 0 {byte >> 4} else {byte & 15}} else {byte};
+    let (mut x, mut y, mut pos) = (0usize, 0usize, 0usize);
+    loop {
+        let (count, value) = match data.get(pos..pos + 2) {
+            Some(&[count, value]) => (count as usize, value),
+            _ => return Err(Error::MalformedRle)
+        };
+        pos += 2;
+        match (count, value) {
+            (0, 0) => {
+                x = 0;
+                y += 1;
             },
-            8 => {
-                DIB::from_bytes_shared(hdr, bytes, 4 * 256, hdr.width() as usize)
+            (0, 1) => break,
+            (0, 2) => {
+                let delta = data.get(pos..pos + 2).ok_or(Error::MalformedRle)?;
+                x += delta[0] as usize;
+                y += delta[1] as usize;
+                pos += 2;
             },
-            24 => {
-                DIB::from_bytes_shared(hdr, bytes, 0, hdr.width() as usize * 3)
+            (0, len) => {
+                let len = len as usize;
+                // usize::div_ceil needs Rust 1.73.
+                #[allow(clippy::manual_div_ceil)]
+                let byte_len = if rle4 {(len + 1) / 2} else {len};
+                let literal = data.get(pos..pos + byte_len).ok_or(Error::MalformedRle)?;
+                for i in 0..len {
+                    let byte = literal.get(if rle4 {i / 2} else {i}).ok_or(Error::MalformedRle)?;
+                    put(x, y, nibble(*byte, i))?;
+                    x += 1;
+                }
+                pos += byte_len + byte_len % 2;
             },
-            32 => {
-                DIB::from_bytes_shared(hdr, bytes, 0, hdr.width() as usize * 4)
+            (count, value) => {
+                for i in 0..count {
+                    put(x, y, nibble(value, i))?;
+                    x += 1;
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn channel(value: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+    let bits = (mask >> mask.trailing_zeros()).count_ones();
+    let value = (value & mask) >> mask.trailing_zeros();
+    if bits >= 8 {
+        (value >> (bits - 8)) as u8
+    } else {
+        (value as u64 * 255 / ((1 << bits) - 1)).min(255) as u8
+    }
+}
+
+impl<'a> DIB<'a> {
+    // Indices past the colour table draw black, like Windows does; decode_dib
+    // reports them once per image.
+    fn palette_color(&self, idx: usize) -> (u8, u8, u8) {
+        let entry = idx * self.palette_entry_size;
+        match self.palette.get(entry..entry + 3) {
+            Some(color) => (color[2], color[1], color[0]),
+            None => {
+                self.bad_index.set(true);
+                (0, 0, 0)
             }
-            _ => Err(Error::UnrecognizedBPP)
         }
     }
-    fn decode(&self) -> Vec<u8> {
+    fn xor_bytes(&self, offset: usize, len: usize) -> Result<&[u8]> {
+        self.xor_mask.get(offset..offset + len).ok_or_else(out_of_bounds)
+    }
+    fn and_bit(&self, x: usize, rym: usize) -> Result<u8> {
+        let byte = self.and_mask.get(rym + x / 8).ok_or_else(out_of_bounds)?;
+        Ok((byte >> (7 - (x % 8))) & 1)
+    }
+    fn pixel_at_1bpp(&self, x: usize, ry: usize, rym: usize) -> Result<Pixel> {
+        let idx = (self.xor_bytes(ry + x / 8, 1)?[0] >> (7 - (x % 8))) as usize & 1;
+        let (red, green, blue) = self.palette_color(idx);
+        let alpha = self.and_bit(x, rym)?;
+        Ok(Pixel {red, green, blue, alpha: if alpha == 0 {255} else {0}})
+    }
+    fn pixel_at_4bpp(&self, x: usize, ry: usize, rym: usize) -> Result<Pixel> {
+        let idx = (self.xor_bytes(ry + x / 2, 1)?[0] >> (if x & 1 == 0 {4} else {0})) as usize & 15;
+        let (red, green, blue) = self.palette_color(idx);
+        let alpha = self.and_bit(x, rym)?;
+        Ok(Pixel {red, green, blue, alpha: if alpha == 0 {255} else {0}})
+    }
+    fn pixel_at_8bpp(&self, x: usize, ry: usize, rym: usize) -> Result<Pixel> {
+        let idx = self.xor_bytes(ry + x, 1)?[0] as usize;
+        let (red, green, blue) = self.palette_color(idx);
+        let alpha = self.and_bit(x, rym)?;
+        Ok(Pixel {red, green, blue, alpha: if alpha == 0 {255} else {0}})
+    }
+    fn pixel_at_24bpp(&self, x: usize, ry: usize, rym: usize) -> Result<Pixel> {
+        let bgr = self.xor_bytes(ry + x * 3, 3)?;
+        let alpha = self.and_bit(x, rym)?;
+        Ok(Pixel {red: bgr[2], green: bgr[1], blue: bgr[0], alpha: if alpha == 0 {255} else {0}})
+    }
+    fn pixel_at_32bpp(&self, x: usize, ry: usize, rym: usize) -> Result<Pixel> {
+        let bgra = self.xor_bytes(ry + x * 4, 4)?;
+        let alpha = bgra[3];
+        let mask = self.and_bit(x, rym)?;
+        Ok(Pixel {red: bgra[2], green: bgra[1], blue: bgra[0], alpha: if alpha == 0 && mask == 0 {255} else {alpha}})
+    }
+    fn pixel_from_masks(&self, value: u32, x: usize, rym: usize) -> Result<Pixel> {
+        let [red, green, blue, alpha] = self.masks;
+        let mask = self.and_bit(x, rym)?;
+        let alpha = if alpha != 0 {
+            let alpha = channel(value, alpha);
+            if alpha == 0 && mask == 0 {255} else {alpha}
+        } else if mask == 0 {
+            255
+        } else {
+            0
+        };
+        Ok(Pixel {red: channel(value, red), green: channel(value, green), blue: channel(value, blue), alpha})
+    }
+    fn pixel_at_16bpp(&self, x: usize, ry: usize, rym: usize) -> Result<Pixel> {
+        let value = self.xor_bytes(ry + x * 2, 2)?;
+        self.pixel_from_masks(u16::from_le_bytes([value[0], value[1]]) as u32, x, rym)
+    }
+    fn pixel_at_32bpp_bitfields(&self, x: usize, ry: usize, rym: usize) -> Result<Pixel> {
+        let value = self.xor_bytes(ry + x * 4, 4)?;
+        self.pixel_from_masks(u32::from_le_bytes([value[0], value[1], value[2], value[3]]), x, rym)
+    }
+    fn from_bytes<'b>(hdr: &'b BitmapInfoHeader, bytes: &'b [u8], limits: &Limits) -> Result<DIB<'b>> {
+        // Before any size arithmetic, so absurd dimensions fail the same way
+        // whether or not they would also overflow
+        limits.check(hdr.width().unsigned_abs() as u64, hdr.height().unsigned_abs() as u64 / 2)?;
+        let header_size = hdr.header_size();
+        let (header_size, masks) = match (hdr.compression(), hdr.bit_count()) {
+            (BI_RGB, 16) => (header_size, [0x7c00, 0x03e0, 0x001f, 0]),
+            (BI_RGB, _) => (header_size, [0; 4]),
+            (BI_RLE8, 8) | (BI_RLE4, 4) => (header_size, [0; 4]),
+            (BI_BITFIELDS, 16) | (BI_BITFIELDS, 32) if !hdr.is_os2() => {
+                if let Some(masks) = hdr.color_masks() {
+                    (header_size, masks)
+                } else {
+                    let masks = bytes.get(header_size..header_size + 12).ok_or_else(out_of_bounds)?;
+                    let mask = |i: usize| u32::from_le_bytes(masks[i * 4..i * 4 + 4].try_into().unwrap());
+                    (header_size + 12, [mask(0), mask(1), mask(2), 0])
+                }
+            },
+            _ => return Err(Error::UnknownCompression)
+        };
+        if ![1, 4, 8, 16, 24, 32].contains(&hdr.bit_count()) {
+            return Err(Error::UnrecognizedBPP);
+        }
+        let entry_size = hdr.palette_entry_size();
+        let palette_size = match (hdr.colors_used() as usize, hdr.bit_count()) {
+            (0, bit_count) if bit_count <= 8 => Some(entry_size << bit_count),
+            (colors_used, _) => entry_size.checked_mul(colors_used)
+        }.ok_or(Error::InvalidDimensions)?;
+        let rle = hdr.compression() == BI_RLE8 || hdr.compression() == BI_RLE4;
+        if rle && (hdr.image_size() == 0 || hdr.height() < 0) {
+            return Err(Error::MalformedRle);
+        }
+        let compressed_size = if rle {Some(hdr.image_size() as usize)} else {None};
+        let dib = DIB::from_bytes_shared(hdr, bytes, compressed_size, header_size, palette_size)?;
+        let xor_mask = if rle {
+            let rle4 = hdr.compression() == BI_RLE4;
+            Cow::Owned(decode_rle(&dib.xor_mask, rle4, dib.width, dib.real_height, dib.row_size)?)
+        } else {
+            dib.xor_mask
+        };
+        Ok(DIB {
+            xor_mask, masks,
+            bitfields: hdr.compression() == BI_BITFIELDS,
+            palette_entry_size: entry_size,
+            ..dib
+        })
+    }
+    fn decode(&self) -> Result<Vec<u8>> {
         let mut pixels = Vec::with_capacity(self.real_height * self.width * 4);
         for y in 0..self.real_height {
             for x in 0..self.width {
@@ -117,108 +266,176 @@ impl<'a> DIB<'a> {
                 };
                 match self.bit_count { // hoping that loop unswitching will kick in here
                     1 => {
-                        self.pixel_at_1bpp(x, ry, rym).copy_to_vec(&mut pixels);
+                        self.pixel_at_1bpp(x, ry, rym)?.copy_to_vec(&mut pixels);
                     },
                     4 => {
-                        self.pixel_at_4bpp(x, ry, rym).copy_to_vec(&mut pixels);
+                        self.pixel_at_4bpp(x, ry, rym)?.copy_to_vec(&mut pixels);
                     },
                     8 => {
-                        self.pixel_at_8bpp(x, ry, rym).copy_to_vec(&mut pixels);
+                        self.pixel_at_8bpp(x, ry, rym)?.copy_to_vec(&mut pixels);
+                    },
+                    16 => {
+                        self.pixel_at_16bpp(x, ry, rym)?.copy_to_vec(&mut pixels);
                     },
                     24 => {
-                        self.pixel_at_24bpp(x, ry, rym).copy_to_vec(&mut pixels);
+                        self.pixel_at_24bpp(x, ry, rym)?.copy_to_vec(&mut pixels);
+                    },
+                    32 if self.bitfields => {
+                        self.pixel_at_32bpp_bitfields(x, ry, rym)?.copy_to_vec(&mut pixels);
                     },
                     32 => {
-                        self.pixel_at_32bpp(x, ry, rym).copy_to_vec(&mut pixels);
+                        self.pixel_at_32bpp(x, ry, rym)?.copy_to_vec(&mut pixels);
                     },
                     _ => {
-                        unreachable!();
+                        return Err(Error::UnrecognizedBPP);
                     }
                 }
             }
         }
-        pixels
+        Ok(pixels)
     }
 
-    fn from_bytes_shared<'b>(hdr: &'b BitmapInfoHeader, bytes: &'b [u8], palette_size: usize, mut row_size: usize) -> Result<DIB<'b>> {
-        let header_size = 40;
-        let image_data_offset = header_size + palette_size;
-        let real_height = (hdr.height().abs() / 2) as usize;
-        if row_size % 4 != 0 {
-            row_size += 4 - row_size % 4;
-        }
-        let xor_mask_size = row_size * real_height;
-        let and_mask_offset = image_data_offset + xor_mask_size;
-        let mut mask_row_size = hdr.width() as usize / 8 + if hdr.width() as usize % 8 != 0 {1} else {0};
-        if mask_row_size % 4 != 0 {
-            mask_row_size += 4 - mask_row_size % 4;
-        }
-        let and_mask_size = mask_row_size * real_height;
-        let image_end = and_mask_offset + and_mask_size;
-        let and_mask = bytes.get(and_mask_offset..image_end).ok_or_else(|| Error::from(Bounds))?;
-        let palette = &bytes[header_size..image_data_offset]; // first check dominates the following checks
-        let xor_mask = &bytes[image_data_offset..and_mask_offset]; // keep it above them to use only one .get
+    // every size below comes straight from the file, so all the arithmetic is checked
+    fn from_bytes_shared<'b>(hdr: &'b BitmapInfoHeader, bytes: &'b [u8], compressed_size: Option<usize>,
+                             header_size: usize, palette_size: usize) -> Result<DIB<'b>> {
+        let width = match hdr.width() {
+            width if width > 0 => width as usize,
+            _ => return Err(Error::InvalidDimensions)
+        };
+        let real_height = match hdr.height().checked_abs() {
+            Some(height) if height >= 2 => height as usize / 2,
+            _ => return Err(Error::InvalidDimensions)
+        };
+        let row_size = stride(width, hdr.bit_count() as usize).ok_or(Error::InvalidDimensions)?;
+        let mask_row_size = stride(width, 1).ok_or(Error::InvalidDimensions)?;
+        let image_data_offset = header_size.checked_add(palette_size).ok_or(Error::InvalidDimensions)?;
+        let xor_mask_size = match compressed_size {
+            Some(size) => Some(size),
+            None => row_size.checked_mul(real_height)
+        }.ok_or(Error::InvalidDimensions)?;
+        let and_mask_offset = image_data_offset.checked_add(xor_mask_size).ok_or(Error::InvalidDimensions)?;
+        let and_mask_size = mask_row_size.checked_mul(real_height).ok_or(Error::InvalidDimensions)?;
+        let image_end = and_mask_offset.checked_add(and_mask_size).ok_or(Error::InvalidDimensions)?;
+        width.checked_mul(real_height).and_then(|pixels| pixels.checked_mul(4)).ok_or(Error::InvalidDimensions)?;
+        let and_mask = bytes.get(and_mask_offset..image_end).ok_or_else(out_of_bounds)?;
+        let palette = bytes.get(header_size..image_data_offset).ok_or_else(out_of_bounds)?;
+        let xor_mask = Cow::Borrowed(bytes.get(image_data_offset..and_mask_offset).ok_or_else(out_of_bounds)?);
         let upside_down = hdr.height() > 0;
         Ok(DIB {
             palette, xor_mask, and_mask, row_size, mask_row_size,
-            real_height, upside_down, width: hdr.width() as usize,
-            bit_count: hdr.bit_count()
+            real_height, upside_down, width,
+            bit_count: hdr.bit_count(),
+            bitfields: false,
+            masks: [0; 4],
+            palette_entry_size: 4,
+            bad_index: Cell::new(false)
         })
     }
 }
 
-pub(crate) fn decode_dib(bytes: &[u8]) -> Result<Vec<u8>> {
+// Problems that still leave a usable image end up in warnings.
+pub fn decode_dib(bytes: &[u8], limits: &Limits, warnings: &mut Vec<Error>) -> Result<(Vec<u8>, u32, u32)> {
     let hdr = BitmapInfoHeader::from_bytes(bytes)?;
-    let dib = DIB::from_bytes(&hdr, bytes)?;
-    Ok(dib.decode())
+    let dib = DIB::from_bytes(&hdr, bytes, limits)?;
+    let pixels = dib.decode()?;
+    if dib.bad_index.get() {
+        warnings.push(Error::PaletteIndex);
+    }
+    Ok((pixels, dib.width as u32, dib.real_height as u32))
 }
 
+const CORE_HEADER_SIZE: usize = 12;
+const INFO_HEADER_SIZE: usize = 40;
+const V5_HEADER_SIZE: usize = 124;
+const PROFILE_EMBEDDED: u32 = 0x4d42_4544; // 'MBED'
+
 impl<'a> BitmapInfoHeader<'a> {
-    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<BitmapInfoHeader> {
-        if bytes.len() >= 40 {
-            Ok(BitmapInfoHeader {
-                bytes: &bytes[..40]
-            })
-        } else {
-            Err(Bounds.into())
+    // Accepts BITMAPCOREHEADER (12), BITMAPINFOHEADER and its extensions up to
+    // the OS/2 2.x header (16..=64), BITMAPV4HEADER (108) and BITMAPV5HEADER (124)
+    pub fn from_bytes(bytes: &[u8]) -> Result<BitmapInfoHeader<'_>> {
+        let size = match bytes.get(0..4) {
+            Some(size) => u32::from_le_bytes(size.try_into().unwrap()) as usize,
+            None => return Err(Bounds.into())
+        };
+        if !matches!(size, CORE_HEADER_SIZE | 16..=64 | 108 | V5_HEADER_SIZE) {
+            return Err(Error::UnknownHeader);
         }
+        let header = bytes.get(..size).ok_or(Bounds)?;
+        Ok(BitmapInfoHeader {bytes: header, dib: bytes})
+    }
+    fn u16_at(&self, offset: usize) -> u16 {
+        self.bytes.get(offset..offset + 2).map_or(0, |b| u16::from_le_bytes(b.try_into().unwrap()))
+    }
+    fn u32_at(&self, offset: usize) -> u32 {
+        self.bytes.get(offset..offset + 4).map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+    pub fn is_core(&self) -> bool {
+        self.bytes.len() == CORE_HEADER_SIZE
+    }
+    // OS/2 2.x headers reuse compression ids 3 and 4 for Huffman 1D and RLE24
+    pub fn is_os2(&self) -> bool {
+        !matches!(self.bytes.len(), CORE_HEADER_SIZE | INFO_HEADER_SIZE | 52 | 56 | 108 | V5_HEADER_SIZE)
+    }
+    pub fn size(&self) -> u32 {
+        self.u32_at(0)
+    }
+    pub fn width(&self) -> i32 {
+        if self.is_core() {self.u16_at(4) as i32} else {self.u32_at(4) as i32}
+    }
+    pub fn height(&self) -> i32 {
+        if self.is_core() {self.u16_at(6) as i32} else {self.u32_at(8) as i32}
     }
-    pub(crate) fn size(&self) -> u32 {
-        u32::from_le_bytes(self.bytes[0..4].try_into().unwrap())
+    pub fn planes(&self) -> u16 {
+        if self.is_core() {self.u16_at(8)} else {self.u16_at(12)}
     }
-    pub(crate) fn width(&self) -> i32 {
-        i32::from_le_bytes(self.bytes[4..8].try_into().unwrap())
+    pub fn bit_count(&self) -> u16 {
+        if self.is_core() {self.u16_at(10)} else {self.u16_at(14)}
     }
-    pub(crate) fn height(&self) -> i32 {
-        i32::from_le_bytes(self.bytes[8..12].try_into().unwrap())
+    pub fn compression(&self) -> u32 {
+        if self.is_core() {0} else {self.u32_at(16)}
     }
-    pub(crate) fn planes(&self) -> u16 {
-        u16::from_le_bytes(self.bytes[12..14].try_into().unwrap())
+    pub fn image_size(&self) -> u32 {
+        if self.is_core() {0} else {self.u32_at(20)}
     }
-    pub(crate) fn bit_count(&self) -> u16 {
-        u16::from_le_bytes(self.bytes[14..16].try_into().unwrap())
+    pub fn x_px_per_meter(&self) -> i32 {
+        if self.is_core() {0} else {self.u32_at(24) as i32}
     }
-    pub(crate) fn compression(&self) -> u32 {
-        u32::from_le_bytes(self.bytes[16..20].try_into().unwrap())
+    pub fn y_px_per_meter(&self) -> i32 {
+        if self.is_core() {0} else {self.u32_at(28) as i32}
     }
-    pub(crate) fn image_size(&self) -> u32 {
-        u32::from_le_bytes(self.bytes[20..24].try_into().unwrap())
+    pub fn colors_used(&self) -> u32 {
+        if self.is_core() {0} else {self.u32_at(32)}
     }
-    pub(crate) fn x_px_per_meter(&self) -> i32 {
-        i32::from_le_bytes(self.bytes[24..28].try_into().unwrap())
+    pub fn colors_important(&self) -> u32 {
+        if self.is_core() {0} else {self.u32_at(36)}
     }
-    pub(crate) fn y_px_per_meter(&self) -> i32 {
-        i32::from_le_bytes(self.bytes[28..32].try_into().unwrap())
+    pub fn header_size(&self) -> usize {
+        self.bytes.len()
     }
-    pub(crate) fn colors_used(&self) -> u32 {
-        u32::from_le_bytes(self.bytes[32..36].try_into().unwrap())
+    pub fn palette_entry_size(&self) -> usize {
+        if self.is_core() {3} else {4}
     }
-    pub(crate) fn colors_important(&self) -> u32 {
-        u32::from_le_bytes(self.bytes[36..40].try_into().unwrap())
+    // red, green, blue and alpha masks stored inside BITMAPV2/V3/V4/V5 headers
+    pub fn color_masks(&self) -> Option<[u32; 4]> {
+        if self.is_os2() || self.bytes.len() < 52 {
+            None
+        } else {
+            Some([self.u32_at(40), self.u32_at(44), self.u32_at(48), self.u32_at(52)])
+        }
+    }
+    pub fn color_space_type(&self) -> u32 {
+        if self.bytes.len() >= 108 {self.u32_at(56)} else {0}
+    }
+    pub fn icc_profile(&self) -> Option<&'a [u8]> {
+        if self.bytes.len() < V5_HEADER_SIZE || self.color_space_type() != PROFILE_EMBEDDED {
+            return None;
+        }
+        let offset = self.u32_at(112) as usize;
+        let size = self.u32_at(116) as usize;
+        self.dib.get(offset..offset.checked_add(size)?)
     }
 }
 
-
 impl<'a> fmt::Debug for BitmapInfoHeader<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("BitmapInfoHeader")
@@ -233,6 +450,235 @@ impl<'a> fmt::Debug for BitmapInfoHeader<'a> {
             .field("y_px_per_meter", &self.y_px_per_meter())
             .field("colors_used", &self.colors_used())
             .field("colors_important", &self.colors_important())
+            .field("color_masks", &self.color_masks())
+            .field("color_space_type", &self.color_space_type())
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rle8(data: &[u8], width: usize, height: usize) -> Result<Vec<u8>> {
+        decode_rle(data, false, width, height, stride(width, 8).unwrap())
+    }
+
+    fn rle4(data: &[u8], width: usize, height: usize) -> Result<Vec<u8>> {
+        decode_rle(data, true, width, height, stride(width, 4).unwrap())
+    }
+
+    // A header of the given size with the BITMAPINFOHEADER fields that fit filled
+    // in; the height is doubled for the AND mask like in icon resources.
+    fn header(size: usize, width: i32, height: i32, bit_count: u16, compression: u32, colors_used: u32) -> Vec<u8> {
+        let mut header = vec![0u8; size.max(INFO_HEADER_SIZE)];
+        header[0..4].copy_from_slice(&(size as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&width.to_le_bytes());
+        header[8..12].copy_from_slice(&(height * 2).to_le_bytes());
+        header[12..14].copy_from_slice(&1u16.to_le_bytes());
+        header[14..16].copy_from_slice(&bit_count.to_le_bytes());
+        header[16..20].copy_from_slice(&compression.to_le_bytes());
+        header[32..36].copy_from_slice(&colors_used.to_le_bytes());
+        header.truncate(size);
+        header
+    }
+
+    fn decode(bytes: &[u8]) -> (Vec<u8>, Vec<Error>) {
+        let mut warnings = Vec::new();
+        let (pixels, _, _) = decode_dib(bytes, &Limits::default(), &mut warnings).unwrap();
+        (pixels, warnings)
+    }
+
+    #[test]
+    fn rle8_runs_and_end_of_line() {
+        let rows = rle8(&[2, 7, 1, 8, 0, 0, 4, 9, 0, 1], 4, 3).unwrap();
+        assert_eq!(rows, [7, 7, 8, 0, 9, 9, 9, 9, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rle8_delta_skips_pixels() {
+        let rows = rle8(&[0, 2, 1, 1, 1, 5, 0, 2, 1, 1, 1, 6, 0, 1], 4, 3).unwrap();
+        assert_eq!(rows, [0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 6]);
+    }
+
+    #[test]
+    fn odd_literals_are_padded_to_words() {
+        let rows = rle8(&[0, 3, 1, 2, 3, 0, 1, 4, 0, 1], 4, 1).unwrap();
+        assert_eq!(rows, [1, 2, 3, 4]);
+        // Five nibbles take three bytes, plus one byte of padding.
+        let rows = rle4(&[0, 5, 0x34, 0x56, 0x70, 0, 1, 0x80, 0, 1], 6, 1).unwrap();
+        assert_eq!(rows, [0x34, 0x56, 0x78, 0]);
+    }
+
+    #[test]
+    fn rle4_runs_alternate_nibbles() {
+        let rows = rle4(&[5, 0x12, 0, 0, 2, 0xf0, 0, 1], 5, 2).unwrap();
+        assert_eq!(rows, [0x12, 0x12, 0x10, 0, 0xf0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn overruns_are_errors() {
+        // A run, a literal and a delta past the right edge
+        assert!(matches!(rle8(&[5, 1, 0, 1], 4, 1), Err(Error::MalformedRle)));
+        assert!(matches!(rle8(&[0, 5, 1, 2, 3, 4, 5, 0, 0, 1], 4, 1), Err(Error::MalformedRle)));
+        assert!(matches!(rle4(&[0, 2, 4, 0, 1, 1, 0, 1], 4, 1), Err(Error::MalformedRle)));
+        // Pixels below the last row, after an end of line or a delta
+        assert!(matches!(rle8(&[0, 0, 1, 1, 0, 1], 4, 1), Err(Error::MalformedRle)));
+        assert!(matches!(rle8(&[0, 2, 0, 3, 1, 1, 0, 1], 4, 3), Err(Error::MalformedRle)));
+        // Moving below the last row is fine as long as nothing is drawn there.
+        assert!(rle8(&[0, 0, 0, 1], 4, 1).is_ok());
+    }
+
+    #[test]
+    fn truncated_streams_are_errors() {
+        assert!(matches!(rle8(&[0, 4, 1, 2], 4, 1), Err(Error::MalformedRle)));
+        assert!(matches!(rle8(&[0, 2, 1], 4, 1), Err(Error::MalformedRle)));
+        // No end of bitmap marker
+        assert!(matches!(rle8(&[2, 7], 4, 1), Err(Error::MalformedRle)));
+        assert!(matches!(rle8(&[], 4, 1), Err(Error::MalformedRle)));
+    }
+
+    #[test]
+    fn indices_past_the_palette_are_black() {
+        let palette = [255, 0, 0, 0, 0, 0, 255, 0];
+        let icon = |index: u8| [&header(40, 1, 1, 8, BI_RGB, 2)[..], &palette, &[index, 0, 0, 0], &[0; 4]].concat();
+        let (pixels, warnings) = decode(&icon(1));
+        assert_eq!(pixels, [255, 0, 0, 255]);
+        assert!(warnings.is_empty());
+        let (pixels, warnings) = decode(&icon(5));
+        assert_eq!(pixels, [0, 0, 0, 255]);
+        assert!(matches!(warnings[..], [Error::PaletteIndex]));
+    }
+
+    fn words(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn rgb555_is_the_default_16bpp_layout() {
+        let pixels = [0x7c00u16, 0x03e0].iter().flat_map(|value| value.to_le_bytes()).collect::<Vec<_>>();
+        let (pixels, _) = decode(&[&header(40, 2, 1, 16, BI_RGB, 0)[..], &pixels, &[0; 4]].concat());
+        assert_eq!(pixels, [255, 0, 0, 255, 0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn rgb565_masks_follow_the_header() {
+        let masks = words(&[0xf800, 0x07e0, 0x001f]);
+        let pixels = [0x07e0u16, 0x0800].iter().flat_map(|value| value.to_le_bytes()).collect::<Vec<_>>();
+        let (pixels, _) = decode(&[&header(40, 2, 1, 16, BI_BITFIELDS, 0)[..], &masks, &pixels, &[0; 4]].concat());
+        // Five and six bit channels are scaled up to the full byte range.
+        assert_eq!(pixels, [0, 255, 0, 255, 8, 0, 0, 255]);
+    }
+
+    #[test]
+    fn bitfields_alpha_mask() {
+        let mut header = header(56, 2, 1, 32, BI_BITFIELDS, 0);
+        header[40..56].copy_from_slice(&words(&[0x0000_00ff, 0x0000_ff00, 0x00ff_0000, 0xff00_0000]));
+        let pixels = [0x11, 0x22, 0x33, 0x80, 0x11, 0x22, 0x33, 0];
+        let (pixels, _) = decode(&[&header[..], &pixels, &[0; 4]].concat());
+        // Zero alpha with a clear AND mask bit is drawn opaque.
+        assert_eq!(pixels, [0x11, 0x22, 0x33, 0x80, 0x11, 0x22, 0x33, 255]);
+    }
+
+    #[test]
+    fn header_sizes() {
+        for &size in &[12, 16, 40, 52, 56, 64, 108, 124] {
+            let mut bytes = vec![0; size];
+            bytes[0..4].copy_from_slice(&(size as u32).to_le_bytes());
+            assert_eq!(BitmapInfoHeader::from_bytes(&bytes).unwrap().header_size(), size);
+        }
+        for &size in &[8, 65, 100, 128] {
+            let mut bytes = vec![0; 128];
+            bytes[0..4].copy_from_slice(&(size as u32).to_le_bytes());
+            assert!(matches!(BitmapInfoHeader::from_bytes(&bytes), Err(Error::UnknownHeader)));
+        }
+        // Declaring more header than there are bytes
+        assert!(BitmapInfoHeader::from_bytes(&header(108, 1, 1, 32, BI_RGB, 0)[..60]).is_err());
+    }
+
+    #[test]
+    fn os2_headers_use_the_info_layout() {
+        let short = header(16, 2, 1, 24, BI_RGB, 0);
+        let hdr = BitmapInfoHeader::from_bytes(&short).unwrap();
+        assert!(hdr.is_os2() && !hdr.is_core());
+        assert_eq!((hdr.width(), hdr.height(), hdr.bit_count(), hdr.compression()), (2, 2, 24, BI_RGB));
+        let pixels = [0, 0, 255, 0, 255, 0, 0, 0];
+        let (pixels, _) = decode(&[&short[..], &pixels, &[0; 4]].concat());
+        assert_eq!(pixels, [255, 0, 0, 255, 0, 255, 0, 255]);
+        // Compression 3 is Huffman 1D here, not BI_BITFIELDS.
+        let huffman = header(64, 2, 1, 1, BI_BITFIELDS, 0);
+        assert!(BitmapInfoHeader::from_bytes(&huffman).unwrap().color_masks().is_none());
+        assert!(matches!(decode_dib(&huffman, &Limits::default(), &mut Vec::new()), Err(Error::UnknownCompression)));
+    }
+
+    #[test]
+    fn v4_and_v5_masks_come_from_the_header() {
+        for &size in &[108, 124] {
+            let mut header = header(size, 1, 1, 32, BI_BITFIELDS, 0);
+            header[40..56].copy_from_slice(&words(&[0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0xff00_0000]));
+            let hdr = BitmapInfoHeader::from_bytes(&header).unwrap();
+            assert_eq!(hdr.color_masks(), Some([0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0xff00_0000]));
+            // The pixels follow the header directly, with no separate mask triple.
+            let (pixels, _) = decode(&[&header[..], &[0x33, 0x22, 0x11, 0x80], &[0; 4]].concat());
+            assert_eq!(pixels, [0x11, 0x22, 0x33, 0x80]);
+        }
+        assert!(BitmapInfoHeader::from_bytes(&header(40, 1, 1, 32, BI_RGB, 0)).unwrap().color_masks().is_none());
+    }
+
+    #[test]
+    fn embedded_profile() {
+        let mut header = header(V5_HEADER_SIZE, 1, 1, 32, BI_RGB, 0);
+        header[56..60].copy_from_slice(&PROFILE_EMBEDDED.to_le_bytes());
+        // The profile offset counts from the start of the header.
+        header[112..120].copy_from_slice(&words(&[V5_HEADER_SIZE as u32 + 8, 4]));
+        let bytes = [&header[..], &[0; 8], b"icc!", &[0; 4]].concat();
+        let hdr = BitmapInfoHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(hdr.icc_profile(), Some(&b"icc!"[..]));
+        let hdr = BitmapInfoHeader::from_bytes(&bytes[..V5_HEADER_SIZE + 10]).unwrap();
+        assert_eq!(hdr.icc_profile(), None);
+        header[56..60].copy_from_slice(b"BGRs");
+        assert_eq!(BitmapInfoHeader::from_bytes(&header).unwrap().icc_profile(), None);
+    }
+
+    #[test]
+    fn palette_size_follows_colors_used() {
+        let red = [0, 0, 255, 0];
+        let blue = [255, 0, 0, 0];
+        // Two colours for a 4bpp image: the pixels start right after them.
+        let bytes = [&header(40, 2, 1, 4, BI_RGB, 2)[..], &red, &blue, &[0x10, 0, 0, 0], &[0; 4]].concat();
+        assert_eq!(decode(&bytes).0, [0, 0, 255, 255, 255, 0, 0, 255]);
+        // Four colours for a 1bpp image: the extra ones are skipped over.
+        let bytes = [&header(40, 2, 1, 1, BI_RGB, 4)[..], &red, &blue, &[0xff; 8], &[0x40, 0, 0, 0], &[0; 4]].concat();
+        assert_eq!(decode(&bytes).0, [255, 0, 0, 255, 0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn palette_past_the_end_is_an_error() {
+        for &colors_used in &[1000, u32::MAX] {
+            let bytes = [&header(40, 1, 1, 8, BI_RGB, colors_used)[..], &[0; 16]].concat();
+            let result = decode_dib(&bytes, &Limits::default(), &mut Vec::new());
+            assert!(matches!(result, Err(Error::Pe(Bounds))));
+        }
+    }
+
+    #[test]
+    fn oversized_dimensions_exceed_the_limits() {
+        let limits = Limits::default();
+        // Only the header is there; the limits are checked before the pixel data is looked for.
+        let decode = |width: i32, height: i32| {
+            let mut bytes = header(40, 1, 1, 32, BI_RGB, 0);
+            bytes[4..8].copy_from_slice(&width.to_le_bytes());
+            bytes[8..12].copy_from_slice(&height.to_le_bytes());
+            decode_dib(&bytes, &limits, &mut Vec::new())
+        };
+        assert!(matches!(decode(limits.max_width as i32 + 1, 2), Err(Error::LimitExceeded)));
+        assert!(matches!(decode(1, (limits.max_height as i32 + 1) * 2), Err(Error::LimitExceeded)));
+        assert!(matches!(decode(1, i32::MAX), Err(Error::LimitExceeded)));
+        assert!(matches!(decode(1, i32::MIN), Err(Error::LimitExceeded)));
+        assert!(matches!(decode(1, -(limits.max_height as i32 + 1) * 2), Err(Error::LimitExceeded)));
+        let small = Limits {max_pixels: 15, ..Limits::default()};
+        let bytes = [&header(40, 4, 4, 32, BI_RGB, 0)[..], &[0; 64 + 16]].concat();
+        assert!(matches!(decode_dib(&bytes, &small, &mut Vec::new()), Err(Error::LimitExceeded)));
+        assert!(decode_dib(&bytes, &limits, &mut Vec::new()).is_ok());
+    }
+}
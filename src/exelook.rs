@@ -1,23 +1,36 @@
 use std::{
     io,
+    cmp::{Ordering, Reverse},
     ffi::CStr,
+    path::PathBuf,
     str::Utf8Error,
-    option::NoneError,
-    convert::{From, TryInto}
+    convert::From
 };
 
 use pelite::{
     self,
+    image::{RT_CURSOR, RT_ANICURSOR, RT_ANIICON},
     PeFile,
     FileMap,
-    resources::{Resources, FindError}
+    resources::{Resources, Directory, DirectoryEntry, DataEntry, FindError, Name, group::{GroupIcon, image::GRPICONDIRENTRY}}
 };
 
 use crate::{
+    ani::{self, AnimatedCursor},
+    cfb::{self, CompoundFile},
     dib::{
         self,
         BitmapInfoHeader
-    }
+    },
+    ico::{self, CursorDirEntry, IconDirEntry},
+    lnk,
+    locale,
+    lx::{self, LxFile, LxResource},
+    msi::Database,
+    ne::{self, NeResource},
+    os2,
+    png::{self, PngHeader, is_png},
+    resample
 };
 
 #[derive(Debug)]
@@ -29,7 +42,27 @@ pub enum Error {
     PlanarNotSupported,
     UnrecognizedBPP,
     UnknownCompression,
-    MalformedPng
+    UnknownHeader,
+    InvalidDimensions,
+    PaletteIndex,
+    DirectoryMismatch,
+    LimitExceeded,
+    MalformedRle,
+    MalformedPng,
+    PngChecksum,
+    PngTruncated,
+    UnsupportedPng,
+    MalformedIconFile,
+    MalformedCursor,
+    MalformedAni,
+    UnsupportedAni,
+    MalformedNe,
+    MalformedLx,
+    UnsupportedLx,
+    MalformedOs2Bitmap,
+    MalformedCompoundFile,
+    MalformedMsi,
+    MalformedLnk
 }
 
 impl From<Utf8Error> for Error {
@@ -50,41 +83,146 @@ impl From<pelite::Error> for Error {
     }
 }
 
-impl From<NoneError> for Error {
-    fn from(_err: NoneError) -> Self {
-        Error::NoIconFound
-    }
-}
-
 impl From<FindError> for Error {
     fn from(_err: FindError) -> Self {
         Error::NoIconFound
     }
 }
 
-struct PngHeader<'a> {
-    bytes: &'a [u8]
+// Upper bounds for anything allocated from sizes found in the file
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+    pub max_bytes: usize
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_width: 4096,
+            max_height: 4096,
+            max_pixels: 4096 * 4096,
+            max_bytes: 64 << 20
+        }
+    }
 }
 
-impl<'a> PngHeader<'a> {
-    fn from_bytes<'b>(bytes: &'b [u8]) -> Result<PngHeader<'b>> {
-        if bytes.len() < 24 || bytes[12..16] != [b'I', b'H', b'D', b'R'] {
-            Err(Error::MalformedPng)
+impl Limits {
+    pub fn check(&self, width: u64, height: u64) -> Result<()> {
+        let pixels = width.saturating_mul(height);
+        if width > self.max_width as u64 || height > self.max_height as u64 || pixels > self.max_pixels {
+            return Err(Error::LimitExceeded);
+        }
+        self.check_bytes(pixels.saturating_mul(4))
+    }
+    pub fn check_bytes(&self, bytes: u64) -> Result<()> {
+        if bytes > self.max_bytes as u64 {
+            Err(Error::LimitExceeded)
         } else {
-            Ok(PngHeader {bytes})
+            Ok(())
         }
     }
-    fn width(&self) -> i32 {
-        u32::from_be_bytes(self.bytes[16..20].try_into().unwrap()) as i32
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub size: u32,
+    pub limits: Limits,
+    // Preferred LANGIDs, most preferred first; see locale::select_language.
+    pub languages: Vec<u16>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupId {
+    Id(u32),
+    Name(String)
+}
+
+impl<'a> From<Name<'a>> for GroupId {
+    fn from(name: Name<'a>) -> Self {
+        match name {
+            Name::Id(id) => GroupId::Id(id),
+            Name::Wide(wide) => GroupId::Name(String::from_utf16_lossy(wide)),
+            Name::Str(s) => GroupId::Name(s.to_owned())
+        }
     }
-    fn height(&self) -> i32 {
-        u32::from_be_bytes(self.bytes[20..24].try_into().unwrap()) as i32
+}
+
+// Decoded RGBA pixels, whatever the entry was stored as.
+#[derive(Debug)]
+pub struct IconImage {
+    pub pixels: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub stride: usize
+}
+
+impl IconImage {
+    pub fn scale_to_fit(self, size: u32, limits: &Limits) -> Result<IconImage> {
+        let IconImage {pixels, width, height, stride} = self;
+        let (new_width, new_height) = resample::fit_within(width, height, size);
+        limits.check(new_width as u64, new_height as u64)?;
+        let pixels = resample::resize(&pixels, width, height, stride, new_width, new_height, limits)?;
+        Ok(IconImage {pixels, width: new_width, height: new_height, stride: new_width as usize * 4})
+    }
+}
+
+#[derive(Debug)]
+pub struct Icon {
+    pub image: IconImage,
+    pub group: GroupId,
+    pub language: u16,
+    pub bit_depth: u16,
+    // Only set for cursors.
+    pub hotspot: Option<(u16, u16)>,
+    pub diagnostics: Vec<Diagnostic>
+}
+
+// An icon group or image that was skipped while looking for a usable icon, or
+// whose group directory entry disagrees with its header (DirectoryMismatch).
+// entry is the RT_ICON id of the image, None when the whole group was unusable;
+// group is None for standalone icon files.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub group: Option<GroupId>,
+    pub entry: Option<u16>,
+    pub error: Error
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Dib
+}
+
+// One image of one language of one icon group. width, height and bit_depth come
+// from the group directory; the image header fills in a zero bit count and sizes
+// above 256, and always gives the bit depth of PNG entries.
+#[derive(Debug, Clone)]
+pub struct IconEntry<'a> {
+    pub group: GroupId,
+    pub language: u16,
+    pub id: u16,
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u16,
+    pub format: ImageFormat,
+    pub size: usize,
+    pub directory_mismatch: bool,
+    pub bytes: &'a [u8]
+}
+
+impl<'a> IconEntry<'a> {
+    pub fn decode(&self, limits: &Limits) -> Result<IconImage> {
+        decode_icon(self.bytes, limits, &mut Vec::new()).map(|(image, _)| image)
     }
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
-fn get_resources(bytes: &[u8]) -> Result<Resources> {
+fn get_resources(bytes: &[u8]) -> Result<Resources<'_>> {
     let res = PeFile::from_bytes(bytes)?.resources();
     if let Err(pelite::Error::Null) = res {
         Err(Error::NoIconFound)
@@ -93,57 +231,1160 @@ fn get_resources(bytes: &[u8]) -> Result<Resources> {
     }
 }
 
-fn is_png(bytes: &[u8]) -> bool {
-    bytes.starts_with(&[0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IconKey {
+    width: u32,
+    height: u32,
+    bit_count: u16
+}
+
+impl IconKey {
+    fn from_bytes(icon: &[u8]) -> Result<IconKey> {
+        Ok(if is_png(icon) {
+            let hdr = PngHeader::from_bytes(icon)?;
+            IconKey {width: hdr.width() as u32, height: hdr.height() as u32, bit_count: 64}
+        } else {
+            let hdr = BitmapInfoHeader::from_bytes(icon)?;
+            IconKey {width: hdr.width().max(0) as u32, height: (hdr.height() / 2).unsigned_abs(), bit_count: hdr.bit_count()}
+        })
+    }
+    // bWidth/bHeight store 256 (and anything larger) as 0; a zero wBitCount
+    // leaves bColorCount as the only hint, and 0 there means unknown.
+    fn from_directory(width: u8, height: u8, color_count: u8, bit_count: u16) -> IconKey {
+        let dimension = |value: u8| if value == 0 {256} else {value as u32};
+        let bit_count = match (bit_count, color_count) {
+            (0, colors) => (colors as u32).next_power_of_two().trailing_zeros() as u16,
+            (bits, _) => bits
+        };
+        IconKey {width: dimension(width), height: dimension(height), bit_count}
+    }
+    fn from_entry(entry: &GRPICONDIRENTRY) -> IconKey {
+        IconKey::from_directory(entry.bWidth, entry.bHeight, entry.bColorCount, entry.wBitCount)
+    }
+    fn from_cursor_entry(entry: &CursorDirEntry) -> IconKey {
+        IconKey {width: entry.width as u32, height: entry.height as u32 / 2, bit_count: entry.bit_count}
+    }
+    // .cur files keep the hotspot where icons have planes and bit count.
+    fn from_file_entry(entry: &IconDirEntry, kind: u16) -> IconKey {
+        let bit_count = if kind == ico::CURSOR {0} else {entry.bit_count};
+        IconKey::from_directory(entry.width, entry.height, entry.color_count, bit_count)
+    }
+    fn matches(&self, header: &IconKey, png: bool) -> bool {
+        let dimension = |dir: u32, hdr: u32| dir == hdr || (dir == 256 && hdr > 256);
+        dimension(self.width, header.width) && dimension(self.height, header.height)
+            && (png || self.bit_count == 0 || self.bit_count == header.bit_count)
+    }
+    fn dimension(&self) -> u32 {
+        self.width.max(self.height)
+    }
+    // size == 0 means "as large as possible"; otherwise the smallest image that is
+    // at least size pixels wins, and the largest one if none of them is big enough.
+    // Lower ranks are better.
+    fn rank(&self, size: u32) -> (bool, i64, Reverse<u16>) {
+        let dim = self.dimension() as i64;
+        if size != 0 && dim >= size as i64 {
+            (false, dim, Reverse(self.bit_count))
+        } else {
+            (true, -dim, Reverse(self.bit_count))
+        }
+    }
 }
 
+type Reported = Vec<(usize, Error)>;
 
-fn icon_compare_key(icon: &[u8]) -> Result<impl PartialOrd> {
-    Ok(if is_png(icon) {
-        let hdr = PngHeader::from_bytes(icon)?;
-        (hdr.width(), hdr.height(), 64)
-    } else {
-        let hdr = BitmapInfoHeader::from_bytes(icon)?;
-        (hdr.width(), hdr.height(), hdr.bit_count())
-    })
+// Entries are ranked by their group directory, like Explorer does. The image
+// header only fills in what the directory can't express (a zero bit count, sizes
+// above 256) and flags entries where the two disagree; such an entry keeps its
+// directory rank and is still decoded as whatever its header says. Without a
+// directory the header is all there is.
+fn resolve_key(directory: Option<IconKey>, icon: &[u8]) -> Result<(IconKey, bool)> {
+    let dir = match directory {
+        Some(dir) => dir,
+        None => return Ok((IconKey::from_bytes(icon)?, false))
+    };
+    let hdr = match IconKey::from_bytes(icon) {
+        Ok(hdr) => hdr,
+        Err(_) => return Ok((dir, false))
+    };
+    let dimension = |dir: u32, hdr: u32| if dir == 256 && hdr > 256 {hdr} else {dir};
+    let key = IconKey {
+        width: dimension(dir.width, hdr.width),
+        height: dimension(dir.height, hdr.height),
+        bit_count: if dir.bit_count == 0 {hdr.bit_count} else {dir.bit_count}
+    };
+    Ok((key, !dir.matches(&hdr, is_png(icon))))
+}
+
+// Entries whose image is missing or unusable are not fatal, they are returned
+// (by position in the group) together with directory mismatches so the caller
+// can report them.
+fn rank_icons<'a>(icons: impl Iterator<Item = (Option<IconKey>, Result<&'a [u8]>)>, size: u32) -> (Vec<(usize, &'a [u8])>, Reported) {
+    let mut ranked = Vec::new();
+    let mut reported = Vec::new();
+    for (index, (directory, icon)) in icons.enumerate() {
+        match icon.and_then(|icon| Ok((icon, resolve_key(directory, icon)?))) {
+            Ok((icon, (key, mismatch))) => {
+                if mismatch {
+                    reported.push((index, Error::DirectoryMismatch));
+                }
+                ranked.push((index, icon, key));
+            },
+            Err(err) => reported.push((index, err))
+        }
+    }
+    ranked.sort_by_key(|(_, _, key)| key.rank(size));
+    (ranked.into_iter().map(|(index, icon, _)| (index, icon)).collect(), reported)
 }
 
-fn best_icon<'a>(mut icons: impl Iterator<Item = Result<&'a [u8]>>) -> Result<&'a [u8]> {
-    let mut cur_max: &'a [u8] = if let Some(x) = icons.next() {
-        x?
+pub fn best_icon<'a>(icons: impl Iterator<Item = Result<&'a [u8]>>, size: u32) -> Result<&'a [u8]> {
+    let (ranked, reported) = rank_icons(icons.map(|icon| (None, icon)), size);
+    match ranked.first() {
+        Some(&(_, icon)) => Ok(icon),
+        None => Err(reported.into_iter().next().map_or(Error::NoIconFound, |(_, err)| err))
+    }
+}
+
+fn decode_icon(icon: &[u8], limits: &Limits, warnings: &mut Vec<Error>) -> Result<(IconImage, u16)> {
+    if is_png(icon) {
+        let bit_depth = PngHeader::from_bytes(icon)?.bit_depth();
+        let (pixels, width, height) = png::decode_png(icon, limits)?;
+        Ok((IconImage {pixels, width, height, stride: width as usize * 4}, bit_depth))
     } else {
-        return Err(Error::NoIconFound);
+        let infoheader = BitmapInfoHeader::from_bytes(icon)?;
+        if infoheader.planes() != 1 {
+            return Err(Error::PlanarNotSupported);
+        }
+        let (pixels, width, height) = dib::decode_dib(icon, limits, warnings)?;
+        Ok((IconImage {pixels, width, height, stride: width as usize * 4}, infoheader.bit_count()))
+    }
+}
+
+struct IconGroup<'a> {
+    id: GroupId,
+    language: u16,
+    icons: GroupIcon<'a>
+}
+
+struct CursorGroup {
+    id: GroupId,
+    language: u16,
+    entries: Vec<CursorDirEntry>
+}
+
+// EnumResourceNames order, which is what Explorer walks to find the main icon:
+// named entries first, compared case-insensitively like the resource compiler
+// stores them, then numeric IDs ascending.
+fn group_order(a: &GroupId, b: &GroupId) -> Ordering {
+    match (a, b) {
+        (GroupId::Name(a), GroupId::Name(b)) => {
+            a.to_uppercase().encode_utf16().cmp(b.to_uppercase().encode_utf16())
+                .then_with(|| a.encode_utf16().cmp(b.encode_utf16()))
+        },
+        (GroupId::Name(_), GroupId::Id(_)) => Ordering::Less,
+        (GroupId::Id(_), GroupId::Name(_)) => Ordering::Greater,
+        (GroupId::Id(a), GroupId::Id(b)) => a.cmp(b)
+    }
+}
+
+fn select_data<'a>(dir: &Directory<'a>, languages: &[u16]) -> Result<(u16, DataEntry<'a>)> {
+    let lang_ids = dir.entries().filter_map(|lang| match lang.name() {
+        Ok(Name::Id(id)) => Some(id as u16),
+        _ => None
+    });
+    let lang_id = locale::select_language(lang_ids, languages).ok_or(Error::NoIconFound)?;
+    Ok((lang_id, dir.get_data(Name::Id(lang_id as u32))?))
+}
+
+fn read_icon_group<'a>(resources: &Resources<'a>, id: GroupId, group: DirectoryEntry<'a>, languages: &[u16]) -> Result<IconGroup<'a>> {
+    let languages_dir = group.entry()?.dir().ok_or(Error::NoIconFound)?;
+    let (lang_id, data) = select_data(&languages_dir, languages)?;
+    let icons = GroupIcon::new(*resources, data.bytes()?)?;
+    Ok(IconGroup {id, language: lang_id, icons})
+}
+
+fn read_cursor_group<'a>(id: GroupId, group: DirectoryEntry<'a>, languages: &[u16]) -> Result<CursorGroup> {
+    let languages_dir = group.entry()?.dir().ok_or(Error::NoIconFound)?;
+    let (lang_id, data) = select_data(&languages_dir, languages)?;
+    let entries = ico::read_cursor_group(data.bytes()?)?;
+    Ok(CursorGroup {id, language: lang_id, entries})
+}
+
+fn cursor_image<'a>(resources: &Resources<'a>, id: u16) -> Result<((u16, u16), &'a [u8])> {
+    let cursors = resources.root()?.get_dir(Name::Id(RT_CURSOR as u32))?;
+    let data = cursors.get_dir(Name::Id(id as u32))?.first_data()?;
+    ico::split_hotspot(data.bytes()?)
+}
+
+// Group directory entries (RT_GROUP_ICON or RT_GROUP_CURSOR) in the order
+// Explorer walks them. This is also what ExtractIconEx counts positive indices
+// against.
+fn group_entries<'a>(resources: &Resources<'a>, kind: Name, diagnostics: &mut Vec<Diagnostic>) -> Result<Vec<(GroupId, DirectoryEntry<'a>)>> {
+    let groups = resources.root()?.get_dir(kind)?;
+    let mut named = Vec::new();
+    for group in groups.entries() {
+        match group.name() {
+            Ok(name) => named.push((GroupId::from(name), group)),
+            Err(err) => diagnostics.push(Diagnostic {group: None, entry: None, error: err.into()})
+        }
+    }
+    named.sort_by(|(a, _), (b, _)| group_order(a, b));
+    Ok(named)
+}
+
+// Every icon group, the one Explorer shows first; groups that can't be read are
+// reported and left out instead of failing the whole lookup.
+fn icon_groups<'a>(resources: &Resources<'a>, languages: &[u16], diagnostics: &mut Vec<Diagnostic>) -> Result<Vec<IconGroup<'a>>> {
+    let mut result = Vec::new();
+    for (id, group) in group_entries(resources, Name::GROUP_ICON, diagnostics)? {
+        match read_icon_group(resources, id.clone(), group, languages) {
+            Ok(icon_group) => result.push(icon_group),
+            Err(error) => diagnostics.push(Diagnostic {group: Some(id), entry: None, error})
+        }
+    }
+    Ok(result)
+}
+
+// Decodes the best candidate that works and returns its position along with the
+// image; ids are what diagnostics refer to.
+fn decode_best<'a>(ids: &[u16], icons: impl Iterator<Item = (Option<IconKey>, Result<&'a [u8]>)>, group: Option<&GroupId>, options: &Options, diagnostics: &mut Vec<Diagnostic>) -> Option<(usize, IconImage, u16)> {
+    let (ranked, reported) = rank_icons(icons, options.size);
+    for (index, error) in reported {
+        diagnostics.push(Diagnostic {group: group.cloned(), entry: Some(ids[index]), error});
+    }
+    for (index, icon) in ranked {
+        let mut warnings = Vec::new();
+        let decoded = decode_icon(icon, &options.limits, &mut warnings);
+        for error in warnings {
+            diagnostics.push(Diagnostic {group: group.cloned(), entry: Some(ids[index]), error});
+        }
+        match decoded {
+            Ok((image, bit_depth)) => return Some((index, image, bit_depth)),
+            Err(error) => diagnostics.push(Diagnostic {group: group.cloned(), entry: Some(ids[index]), error})
+        }
+    }
+    None
+}
+
+fn decode_group(group: &IconGroup, options: &Options, diagnostics: &mut Vec<Diagnostic>) -> Option<(IconImage, u16)> {
+    let entries = group.icons.entries();
+    let ids: Vec<u16> = entries.iter().map(|ent| ent.nId).collect();
+    let icons = entries.iter().map(|ent| (Some(IconKey::from_entry(ent)), group.icons.image(ent.nId).map_err(Into::into)));
+    decode_best(&ids, icons, Some(&group.id), options, diagnostics).map(|(_, image, bit_depth)| (image, bit_depth))
+}
+
+fn decode_cursor_group(resources: &Resources, group: &CursorGroup, options: &Options, diagnostics: &mut Vec<Diagnostic>) -> Option<(IconImage, u16, (u16, u16))> {
+    let ids: Vec<u16> = group.entries.iter().map(|entry| entry.id).collect();
+    let mut hotspots = Vec::new();
+    let cursors = group.entries.iter().map(|entry| {
+        let image = cursor_image(resources, entry.id);
+        hotspots.push(image.as_ref().map_or((0, 0), |&(hotspot, _)| hotspot));
+        (Some(IconKey::from_cursor_entry(entry)), image.map(|(_, bytes)| bytes))
+    });
+    let (index, image, bit_depth) = decode_best(&ids, cursors, Some(&group.id), options, diagnostics)?;
+    Some((image, bit_depth, hotspots[index]))
+}
+
+// The first cursor group that decodes, in the same order as icon groups. The
+// returned Icon carries no diagnostics, they are collected by the caller.
+fn main_cursor(resources: &Resources, options: &Options, diagnostics: &mut Vec<Diagnostic>) -> Option<Icon> {
+    let groups = match group_entries(resources, Name::GROUP_CURSOR, diagnostics) {
+        Ok(groups) => groups,
+        Err(Error::NoIconFound) => return None,
+        Err(error) => {
+            diagnostics.push(Diagnostic {group: None, entry: None, error});
+            return None;
+        }
     };
-    let mut cur_max_key = icon_compare_key(cur_max)?;
-    for icon in icons {
-        let icon = icon?;
-        let key = icon_compare_key(icon)?;
-        if key > cur_max_key {
-            cur_max = icon;
-            cur_max_key = key;
+    for (id, entry) in groups {
+        match read_cursor_group(id.clone(), entry, &options.languages) {
+            Ok(group) => {
+                if let Some((image, bit_depth, hotspot)) = decode_cursor_group(resources, &group, options, diagnostics) {
+                    let CursorGroup {id, language, ..} = group;
+                    return Some(Icon {image, group: id, language, bit_depth, hotspot: Some(hotspot), diagnostics: Vec::new()});
+                }
+            },
+            Err(error) => diagnostics.push(Diagnostic {group: Some(id), entry: None, error})
         }
     }
-    Ok(cur_max)
+    None
+}
+
+// Standalone .ico/.cur files go through the same selection as icon groups. There
+// is no group or language, so the result reports Id(0) and language 0, and
+// diagnostics refer to entries by their position in the file.
+pub fn read_icon_file(bytes: &[u8], options: &Options) -> Result<Icon> {
+    let (kind, entries) = ico::read_icon_dir(bytes)?;
+    let ids: Vec<u16> = (0..entries.len() as u16).collect();
+    let icons = entries.iter().map(|entry| (Some(IconKey::from_file_entry(&entry.dir, kind)), ico::image(bytes, entry)));
+    let mut diagnostics = Vec::new();
+    match decode_best(&ids, icons, None, options, &mut diagnostics) {
+        Some((index, image, bit_depth)) => {
+            let hotspot = if kind == ico::CURSOR {Some(entries[index].dir.hotspot())} else {None};
+            Ok(Icon {image, group: GroupId::Id(0), language: 0, bit_depth, hotspot, diagnostics})
+        },
+        None => Err(first_error(diagnostics))
+    }
 }
 
-pub fn exelook(file_name: &CStr) -> Result<(Vec<u8>, bool, i32, i32)> {
+fn first_error(diagnostics: Vec<Diagnostic>) -> Error {
+    diagnostics.into_iter().next().map_or(Error::NoIconFound, |diag| diag.error)
+}
+
+pub fn exelook(file_name: &CStr, options: &Options) -> Result<Icon> {
     let map_region = FileMap::open(file_name.to_str()?)?;
-    let resources = get_resources(map_region.as_ref())?;
-    let (_, icon_group) = resources.group_icons().next().ok_or(Error::NoIconFound)??;
-    let icons = icon_group.entries().iter().map(|ent| icon_group.image(ent.nId).map_err(Into::into));
+    exelook_bytes(map_region.as_ref(), options)
+}
+
+pub fn exelook_bytes(bytes: &[u8], options: &Options) -> Result<Icon> {
+    if ico::is_icon_file(bytes) {
+        return read_icon_file(bytes, options);
+    }
+    if ani::is_ani(bytes) {
+        return ani::decode_first_frame(bytes, options);
+    }
+    if ne::is_ne(bytes) {
+        return exelook_ne_bytes(bytes, options);
+    }
+    if lx::is_lx(bytes) {
+        return exelook_lx_bytes(bytes, options);
+    }
+    if cfb::is_compound_file(bytes) {
+        return exelook_msi_bytes(bytes, options);
+    }
+    let resources = get_resources(bytes)?;
+    let mut diagnostics = Vec::new();
+    // Having no icon groups isn't an error yet, there may still be a cursor.
+    let groups = match icon_groups(&resources, &options.languages, &mut diagnostics) {
+        Err(Error::NoIconFound) => Vec::new(),
+        groups => groups?
+    };
+    for group in groups {
+        if let Some((image, bit_depth)) = decode_group(&group, options, &mut diagnostics) {
+            let IconGroup {id, language, ..} = group;
+            return Ok(Icon {image, group: id, language, bit_depth, hotspot: None, diagnostics});
+        }
+    }
+    if let Some(mut cursor) = main_cursor(&resources, options, &mut diagnostics) {
+        cursor.diagnostics = diagnostics;
+        return Ok(cursor);
+    }
+    if let Some((id, ani)) = ani_resource(&resources, &options.languages, &mut diagnostics) {
+        match ani::decode_first_frame(ani, options) {
+            Ok(mut frame) => {
+                diagnostics.append(&mut frame.diagnostics);
+                frame.diagnostics = diagnostics;
+                return Ok(frame);
+            },
+            Err(error) => diagnostics.push(Diagnostic {group: Some(id), entry: None, error})
+        }
+    }
+    Err(first_error(diagnostics))
+}
 
-    let best_icon = best_icon(icons)?;
-    if is_png(best_icon) {
-        Ok((best_icon.to_owned(), true, 0, 0))
+// Animated cursors and icons are embedded whole, the same bytes as an .ani file.
+// Besides the standard types some linkers use a custom "ANICURSOR" type.
+fn ani_resource<'a>(resources: &Resources<'a>, languages: &[u16], diagnostics: &mut Vec<Diagnostic>) -> Option<(GroupId, &'a [u8])> {
+    let kinds = [Name::Id(RT_ANICURSOR as u32), Name::Str("ANICURSOR"), Name::Id(RT_ANIICON as u32)];
+    for kind in kinds.iter() {
+        // Most files have none of these types, that alone isn't worth reporting.
+        let groups = match group_entries(resources, *kind, diagnostics) {
+            Ok(groups) => groups,
+            Err(Error::NoIconFound) => continue,
+            Err(error) => {
+                diagnostics.push(Diagnostic {group: None, entry: None, error});
+                continue;
+            }
+        };
+        for (id, entry) in groups {
+            let data = entry.entry().ok().and_then(|entry| entry.dir()).ok_or(Error::NoIconFound)
+                .and_then(|dir| Ok(select_data(&dir, languages)?.1.bytes()?));
+            match data {
+                Ok(bytes) if ani::is_ani(bytes) => return Some((id, bytes)),
+                Ok(_) => diagnostics.push(Diagnostic {group: Some(id), entry: None, error: Error::MalformedAni}),
+                Err(error) => diagnostics.push(Diagnostic {group: Some(id), entry: None, error})
+            }
+        }
+    }
+    None
+}
+
+// Standalone .ani files, or the first animated cursor embedded in an executable.
+pub fn exelook_ani_bytes(bytes: &[u8], options: &Options) -> Result<AnimatedCursor> {
+    if ani::is_ani(bytes) {
+        return ani::decode_ani(bytes, options);
+    }
+    let resources = get_resources(bytes)?;
+    let mut diagnostics = Vec::new();
+    match ani_resource(&resources, &options.languages, &mut diagnostics) {
+        Some((_, ani)) => ani::decode_ani(ani, options),
+        None => Err(first_error(diagnostics))
+    }
+}
+
+// NE icon groups in the same order as PE ones.
+fn ne_icon_groups<'a, 'b>(resources: &'b [NeResource<'a>]) -> Vec<&'b NeResource<'a>> {
+    let mut groups: Vec<&NeResource> = resources.iter().filter(|res| res.kind == GroupId::Id(ne::RT_GROUP_ICON as u32)).collect();
+    groups.sort_by(|a, b| group_order(&a.id, &b.id));
+    groups
+}
+
+fn decode_ne_group(resources: &[NeResource], group: &NeResource, options: &Options, diagnostics: &mut Vec<Diagnostic>) -> Option<(IconImage, u16)> {
+    let entries = match ico::read_icon_group(group.bytes) {
+        Ok(entries) => entries,
+        Err(error) => {
+            diagnostics.push(Diagnostic {group: Some(group.id.clone()), entry: None, error});
+            return None;
+        }
+    };
+    let icon = |id: u16| resources.iter()
+        .find(|res| res.kind == GroupId::Id(ne::RT_ICON as u32) && res.id == GroupId::Id(id as u32));
+    let ids: Vec<u16> = entries.iter().map(|entry| entry.id).collect();
+    let icons = entries.iter().map(|entry| {
+        (Some(IconKey::from_file_entry(&entry.dir, ico::ICON)), icon(entry.id).map(|res| res.bytes).ok_or(Error::NoIconFound))
+    });
+    decode_best(&ids, icons, Some(&group.id), options, diagnostics).map(|(_, image, bit_depth)| (image, bit_depth))
+}
+
+// 16-bit executables and .icl libraries. NE resources have no languages, so the
+// result always reports language 0.
+pub fn exelook_ne_bytes(bytes: &[u8], options: &Options) -> Result<Icon> {
+    let mut diagnostics = Vec::new();
+    let resources = ne::resources(bytes, &mut diagnostics)?;
+    for group in ne_icon_groups(&resources) {
+        if let Some((image, bit_depth)) = decode_ne_group(&resources, group, options, &mut diagnostics) {
+            return Ok(Icon {image, group: group.id.clone(), language: 0, bit_depth, hotspot: None, diagnostics});
+        }
+    }
+    Err(first_error(diagnostics))
+}
+
+fn exelook_ne_bytes_index(bytes: &[u8], index: i32, options: &Options) -> Result<Icon> {
+    let mut diagnostics = Vec::new();
+    let resources = ne::resources(bytes, &mut diagnostics)?;
+    let mut groups = ne_icon_groups(&resources).into_iter();
+    let group = if index < 0 {
+        let id = GroupId::Id(index.unsigned_abs());
+        groups.find(|group| group.id == id)
     } else {
-        let infoheader = BitmapInfoHeader::from_bytes(best_icon)?;
-        if infoheader.planes() != 1 {
-            return Err(Error::PlanarNotSupported);
+        groups.nth(index as usize)
+    }.ok_or(Error::NoIconFound)?;
+    match decode_ne_group(&resources, group, options, &mut diagnostics) {
+        Some((image, bit_depth)) => Ok(Icon {image, group: group.id.clone(), language: 0, bit_depth, hotspot: None, diagnostics}),
+        None => Err(first_error(diagnostics))
+    }
+}
+
+// OS/2 LX executables and LE VxDs. There are no icon groups: every RT_POINTER
+// resource is a complete icon or pointer, possibly an array of sizes, and the
+// one with the lowest ID wins. Like NE there are no languages.
+pub fn exelook_lx_bytes(bytes: &[u8], options: &Options) -> Result<Icon> {
+    let lx = LxFile::parse(bytes)?;
+    let mut pointers: Vec<&LxResource> = lx.resources.iter().filter(|res| res.kind == lx::RT_POINTER).collect();
+    pointers.sort_by_key(|res| res.id);
+    let mut diagnostics = Vec::new();
+    for pointer in pointers {
+        let group = GroupId::Id(pointer.id as u32);
+        let data = match lx.read(pointer, &options.limits) {
+            Ok(data) => data,
+            Err(error) => {
+                diagnostics.push(Diagnostic {group: Some(group), entry: None, error});
+                continue;
+            }
+        };
+        let mut images = Vec::new();
+        for (index, image) in os2::images(&data, &options.limits).into_iter().enumerate() {
+            match image {
+                Ok(image) => images.push((index as u16, image)),
+                Err(error) => diagnostics.push(Diagnostic {group: Some(group.clone()), entry: Some(index as u16), error})
+            }
+        }
+        let ids: Vec<u16> = images.iter().map(|(index, _)| *index).collect();
+        let icons = images.iter().map(|(_, image)| {
+            (Some(IconKey {width: image.width, height: image.height, bit_count: image.bit_count}), Ok(&image.dib[..]))
+        });
+        if let Some((index, image, bit_depth)) = decode_best(&ids, icons, Some(&group), options, &mut diagnostics) {
+            let hotspot = images[index].1.hotspot;
+            return Ok(Icon {image, group, language: 0, bit_depth, hotspot, diagnostics});
+        }
+    }
+    Err(first_error(diagnostics))
+}
+
+// Windows Installer packages. The product icon stream is either an .ico file or
+// an executable; a compound file nested inside is refused rather than recursed
+// into.
+pub fn exelook_msi_bytes(bytes: &[u8], options: &Options) -> Result<Icon> {
+    let file = CompoundFile::parse(bytes, &options.limits)?;
+    let icon = Database::open(&file, &options.limits)?.product_icon()?;
+    if ico::is_icon_file(&icon) {
+        return read_icon_file(&icon, options);
+    }
+    if cfb::is_compound_file(&icon) {
+        return Err(Error::MalformedMsi);
+    }
+    exelook_bytes(&icon, options)
+}
+
+// Shell links. map_path turns a Windows path from the link into a local file,
+// e.g. inside a mounted Windows partition, or returns None to skip it; paths
+// from the environment blocks still contain variables like %SystemRoot%. The
+// candidates are tried in the order of ShellLink::icon_candidates.
+pub fn exelook_lnk_bytes(bytes: &[u8], options: &Options, map_path: impl Fn(&str) -> Option<PathBuf>) -> Result<Icon> {
+    let link = lnk::parse_lnk(bytes)?;
+    let mut diagnostics = Vec::new();
+    for (index, (location, icon_index)) in link.icon_candidates().into_iter().enumerate() {
+        let (path, _) = parse_icon_location(location);
+        let path = match map_path(path) {
+            Some(path) => path,
+            None => continue
+        };
+        let icon = FileMap::open(&path).map_err(Error::from).and_then(|map_region| {
+            let bytes = map_region.as_ref();
+            if icon_index != 0 && !ico::is_icon_file(bytes) {
+                exelook_bytes_index(bytes, icon_index, options)
+            } else {
+                exelook_bytes(bytes, options)
+            }
+        });
+        match icon {
+            Ok(mut icon) => {
+                diagnostics.append(&mut icon.diagnostics);
+                icon.diagnostics = diagnostics;
+                return Ok(icon);
+            },
+            Err(error) => diagnostics.push(Diagnostic {group: None, entry: Some(index as u16), error})
+        }
+    }
+    Err(first_error(diagnostics))
+}
+
+pub fn exelook_cursor_bytes(bytes: &[u8], options: &Options) -> Result<Icon> {
+    let resources = get_resources(bytes)?;
+    let mut diagnostics = Vec::new();
+    match main_cursor(&resources, options, &mut diagnostics) {
+        Some(mut cursor) => {
+            cursor.diagnostics = diagnostics;
+            Ok(cursor)
+        },
+        None => Err(first_error(diagnostics))
+    }
+}
+
+// ExtractIconEx semantics: a non-negative index is the position of the group in
+// Explorer order, a negative one is the negated resource ID. Only that group is
+// considered. Works on PE and NE modules (including .icl libraries).
+pub fn exelook_index(file_name: &CStr, index: i32, options: &Options) -> Result<Icon> {
+    let map_region = FileMap::open(file_name.to_str()?)?;
+    exelook_bytes_index(map_region.as_ref(), index, options)
+}
+
+pub fn exelook_bytes_index(bytes: &[u8], index: i32, options: &Options) -> Result<Icon> {
+    if ne::is_ne(bytes) {
+        return exelook_ne_bytes_index(bytes, index, options);
+    }
+    let resources = get_resources(bytes)?;
+    let mut diagnostics = Vec::new();
+    let mut entries = group_entries(&resources, Name::GROUP_ICON, &mut diagnostics)?.into_iter();
+    let (id, entry) = if index < 0 {
+        let id = GroupId::Id(index.unsigned_abs());
+        entries.find(|(group, _)| *group == id)
+    } else {
+        entries.nth(index as usize)
+    }.ok_or(Error::NoIconFound)?;
+    let group = read_icon_group(&resources, id, entry, &options.languages)?;
+    match decode_group(&group, options, &mut diagnostics) {
+        Some((image, bit_depth)) => Ok(Icon {image, group: group.id, language: group.language, bit_depth, hotspot: None, diagnostics}),
+        None => Err(first_error(diagnostics))
+    }
+}
+
+// Any other resource (RT_VERSION, RT_MANIFEST, ...) resolved through the same
+// language fallback as icon groups.
+pub fn localized_resource<'a>(bytes: &'a [u8], kind: Name, name: Name, languages: &[u16]) -> Result<(u16, &'a [u8])> {
+    let resources = get_resources(bytes)?;
+    let dir = resources.root()?.get_dir(kind)?.get_dir(name)?;
+    let (lang_id, data) = select_data(&dir, languages)?;
+    Ok((lang_id, data.bytes()?))
+}
+
+fn icon_entry<'a>(group: &GroupId, language: u16, entry: &GRPICONDIRENTRY, bytes: &'a [u8]) -> Result<IconEntry<'a>> {
+    let (key, directory_mismatch) = resolve_key(Some(IconKey::from_entry(entry)), bytes)?;
+    let (format, bit_depth) = if is_png(bytes) {
+        (ImageFormat::Png, PngHeader::from_bytes(bytes).map_or(key.bit_count, |hdr| hdr.bit_depth()))
+    } else {
+        (ImageFormat::Dib, key.bit_count)
+    };
+    Ok(IconEntry {
+        group: group.clone(),
+        language,
+        id: entry.nId,
+        width: key.width,
+        height: key.height,
+        bit_depth,
+        format,
+        size: bytes.len(),
+        directory_mismatch,
+        bytes
+    })
+}
+
+// Every language of a group, not just the one selection would pick.
+fn group_images<'a>(resources: Resources<'a>, id: GroupId, group: DirectoryEntry<'a>) -> Vec<::std::result::Result<IconEntry<'a>, Diagnostic>> {
+    let languages = match group.entry().map(|entry| entry.dir()) {
+        Ok(Some(languages)) => languages,
+        Ok(None) => return vec![Err(Diagnostic {group: Some(id), entry: None, error: Error::NoIconFound})],
+        Err(err) => return vec![Err(Diagnostic {group: Some(id), entry: None, error: err.into()})]
+    };
+    let mut images = Vec::new();
+    for language in languages.entries() {
+        let lang_id = match language.name() {
+            Ok(Name::Id(lang_id)) => lang_id as u16,
+            _ => continue
+        };
+        let icons = language.entry().ok().and_then(|entry| entry.data()).ok_or(Error::NoIconFound)
+            .and_then(|data| Ok(GroupIcon::new(resources, data.bytes()?)?));
+        let icons = match icons {
+            Ok(icons) => icons,
+            Err(error) => {
+                images.push(Err(Diagnostic {group: Some(id.clone()), entry: None, error}));
+                continue;
+            }
+        };
+        for entry in icons.entries() {
+            let image = icons.image(entry.nId).map_err(Into::into)
+                .and_then(|bytes| icon_entry(&id, lang_id, entry, bytes));
+            images.push(image.map_err(|error| Diagnostic {group: Some(id.clone()), entry: Some(entry.nId), error}));
+        }
+    }
+    images
+}
+
+// Every image of every icon group in Explorer order, for callers that want more
+// than the single best icon. Pixels are only decoded through IconEntry::decode.
+pub fn icon_entries(bytes: &[u8]) -> Result<impl Iterator<Item = ::std::result::Result<IconEntry<'_>, Diagnostic>>> {
+    let resources = get_resources(bytes)?;
+    let mut diagnostics = Vec::new();
+    let groups = group_entries(&resources, Name::GROUP_ICON, &mut diagnostics)?;
+    let unnamed = diagnostics.into_iter().map(Err);
+    Ok(unnamed.chain(groups.into_iter().flat_map(move |(id, group)| group_images(resources, id, group))))
+}
+
+// Rebuilds the .ico file the group was compiled from. Entries whose image is
+// missing are left out rather than producing a file with dangling offsets.
+pub fn extract_ico(bytes: &[u8], group: &GroupId, languages: &[u16]) -> Result<Vec<u8>> {
+    let resources = get_resources(bytes)?;
+    let (id, entry) = group_entries(&resources, Name::GROUP_ICON, &mut Vec::new())?.into_iter()
+        .find(|(id, _)| id == group).ok_or(Error::NoIconFound)?;
+    let group = read_icon_group(&resources, id, entry, languages)?;
+    let images: Vec<_> = group.icons.entries().iter().filter_map(|entry| {
+        let dir_entry = IconDirEntry {
+            width: entry.bWidth,
+            height: entry.bHeight,
+            color_count: entry.bColorCount,
+            planes: entry.wPlanes,
+            bit_count: entry.wBitCount
+        };
+        group.icons.image(entry.nId).ok().map(|bytes| (dir_entry, bytes))
+    }).collect();
+    if images.is_empty() {
+        return Err(Error::NoIconFound);
+    }
+    Ok(ico::write_ico(&images))
+}
+
+fn group_ids(bytes: &[u8], kind: Name) -> Result<Vec<GroupId>> {
+    let resources = get_resources(bytes)?;
+    let entries = group_entries(&resources, kind, &mut Vec::new())?;
+    Ok(entries.into_iter().map(|(id, _)| id).collect())
+}
+
+pub fn icon_group_ids(bytes: &[u8]) -> Result<Vec<GroupId>> {
+    group_ids(bytes, Name::GROUP_ICON)
+}
+
+pub fn cursor_group_ids(bytes: &[u8]) -> Result<Vec<GroupId>> {
+    group_ids(bytes, Name::GROUP_CURSOR)
+}
+
+// Splits an icon location like `"C:\Program Files\App\app.exe",-101` as found
+// in .lnk files, desktop.ini and DefaultIcon registry values. A missing index
+// means 0.
+pub fn parse_icon_location(location: &str) -> (&str, i32) {
+    let location = location.trim();
+    let (path, index) = match location.rfind(',') {
+        Some(comma) => match location[comma + 1..].trim().parse() {
+            Ok(index) => (&location[..comma], index),
+            Err(_) => (location, 0)
+        },
+        None => (location, 0)
+    };
+    let path = path.trim();
+    let path = path.strip_prefix('"').and_then(|path| path.strip_suffix('"')).unwrap_or(path);
+    (path, index)
+}
+
+#[cfg(test)]
+mod tests {
+    use pelite::image::{RT_ICON, RT_GROUP_ICON, RT_GROUP_CURSOR};
+
+    use super::*;
+
+    fn name(s: &str) -> GroupId {
+        GroupId::Name(s.to_owned())
+    }
+
+    // type -> name -> language -> data, in the order given
+    type Tree = Vec<(u32, Vec<(GroupId, Vec<(u16, Vec<u8>)>)>)>;
+
+    const RSRC_RVA: u32 = 0x1000;
+
+    // pelite reads headers in place and wants them aligned the way a file
+    // mapping would be.
+    struct Image(Vec<u64>, usize);
+
+    impl Image {
+        fn bytes(&self) -> &[u8] {
+            unsafe { std::slice::from_raw_parts(self.0.as_ptr() as *const u8, self.1) }
+        }
+    }
+
+    fn put(bytes: &mut [u8], offset: usize, values: &[u32]) {
+        for (i, value) in values.iter().enumerate() {
+            bytes[offset + i * 4..offset + i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn directory_table(bytes: &mut [u8], offset: usize, named: usize, entries: &[(u32, u32)]) {
+        put(bytes, offset + 12, &[(named | (entries.len() - named) << 16) as u32]);
+        for (i, &(name, target)) in entries.iter().enumerate() {
+            put(bytes, offset + 16 + 8 * i, &[name, target]);
+        }
+    }
+
+    // Directory tables first, then the data entries, then names and data.
+    fn resource_section(tree: &Tree) -> Vec<u8> {
+        let size = |count: usize| 16 + 8 * count;
+        let groups = tree.iter().flat_map(|(_, groups)| groups);
+        let tables = size(tree.len()) + tree.iter().map(|(_, groups)| size(groups.len())).sum::<usize>()
+            + groups.clone().map(|(_, languages)| size(languages.len())).sum::<usize>();
+        let mut section = vec![0u8; tables + 16 * groups.map(|(_, languages)| languages.len()).sum::<usize>()];
+        let mut tail = Vec::new();
+        let (mut next_table, mut next_leaf) = (size(tree.len()), tables);
+        let mut types = Vec::new();
+        for (kind, groups) in tree {
+            let group_table = next_table;
+            types.push((*kind, 0x8000_0000 | group_table as u32));
+            next_table += size(groups.len());
+            let mut entries = Vec::new();
+            for (id, languages) in groups {
+                let name = match id {
+                    GroupId::Id(id) => *id,
+                    GroupId::Name(name) => {
+                        let offset = section.len() + tail.len();
+                        tail.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                        for unit in name.encode_utf16() {
+                            tail.extend_from_slice(&unit.to_le_bytes());
+                        }
+                        0x8000_0000 | offset as u32
+                    }
+                };
+                let language_table = next_table;
+                entries.push((name, 0x8000_0000 | language_table as u32));
+                next_table += size(languages.len());
+                let mut leaves = Vec::new();
+                for (language, data) in languages {
+                    tail.resize((tail.len() + 3) & !3, 0);
+                    let offset = section.len() + tail.len();
+                    put(&mut section, next_leaf, &[RSRC_RVA + offset as u32, data.len() as u32]);
+                    tail.extend_from_slice(data);
+                    leaves.push((*language as u32, next_leaf as u32));
+                    next_leaf += 16;
+                }
+                directory_table(&mut section, language_table, 0, &leaves);
+            }
+            let named = groups.iter().filter(|(id, _)| matches!(id, GroupId::Name(_))).count();
+            directory_table(&mut section, group_table, named, &entries);
+        }
+        directory_table(&mut section, 0, 0, &types);
+        section.extend_from_slice(&tail);
+        section
+    }
+
+    // A PE32 image with nothing but a resource section.
+    fn pe(tree: &Tree) -> Image {
+        let rsrc = resource_section(tree);
+        let raw_size = (rsrc.len() + 0x1ff) & !0x1ff;
+        let virtual_size = (rsrc.len() + 0xfff) & !0xfff;
+        let mut image = vec![0u8; 0x200 + raw_size];
+        image[..2].copy_from_slice(b"MZ");
+        put(&mut image, 0x3c, &[0x40]);
+        image[0x40..0x44].copy_from_slice(b"PE\0\0");
+        // Machine, NumberOfSections, SizeOfOptionalHeader and Characteristics
+        put(&mut image, 0x44, &[0x0001_014c, 0, 0, 0, 0x0102_00e0]);
+        // Magic, sizes, ImageBase, alignments, versions and SizeOfImage/Headers
+        put(&mut image, 0x58, &[0x000e_010b, 0, raw_size as u32, 0, 0, 0, 0, 0x40_0000, 0x1000, 0x200, 6, 0, 6]);
+        put(&mut image, 0x90, &[RSRC_RVA + virtual_size as u32, 0x200, 0, 2, 0x10_0000, 0x1000, 0x10_0000, 0x1000, 0, 16]);
+        put(&mut image, 0xc8, &[RSRC_RVA, rsrc.len() as u32]);
+        image[0x138..0x13e].copy_from_slice(b".rsrc\0");
+        put(&mut image, 0x140, &[virtual_size as u32, RSRC_RVA, raw_size as u32, 0x200, 0, 0, 0, 0x4000_0040]);
+        image[0x200..0x200 + rsrc.len()].copy_from_slice(&rsrc);
+        #[allow(clippy::manual_div_ceil)]
+        let mut words = vec![0u64; (image.len() + 7) / 8];
+        for (word, bytes) in words.iter_mut().zip(image.chunks(8)) {
+            let mut buf = [0u8; 8];
+            buf[..bytes.len()].copy_from_slice(bytes);
+            *word = u64::from_ne_bytes(buf);
+        }
+        Image(words, image.len())
+    }
+
+    // Icon groups by language; an empty group directory is enough to select by.
+    fn icon_tree(groups: Vec<(GroupId, Vec<u16>)>) -> Image {
+        let groups = groups.into_iter().map(|(id, languages)| {
+            (id, languages.into_iter().map(|language| (language, vec![0, 0, 1, 0, 0, 0])).collect())
+        }).collect();
+        pe(&vec![(RT_GROUP_ICON as u32, groups)])
+    }
+
+    fn sorted(ids: Vec<GroupId>) -> Vec<GroupId> {
+        let image = icon_tree(ids.into_iter().map(|id| (id, vec![0x0409])).collect());
+        let resources = get_resources(image.bytes()).unwrap();
+        group_entries(&resources, Name::GROUP_ICON, &mut Vec::new()).unwrap().into_iter().map(|(id, _)| id).collect()
+    }
+
+    fn main_icon(tree: Vec<(GroupId, Vec<u16>)>, languages: &[u16]) -> Option<(GroupId, u16)> {
+        let image = icon_tree(tree);
+        let resources = get_resources(image.bytes()).unwrap();
+        let groups = icon_groups(&resources, languages, &mut Vec::new()).unwrap();
+        groups.into_iter().next().map(|group| (group.id, group.language))
+    }
+
+    #[test]
+    fn named_groups_sort_case_insensitively() {
+        assert_eq!(sorted(vec![name("MAINICON"), name("app"), name("Zeta"), name("b")]),
+                   vec![name("app"), name("b"), name("MAINICON"), name("Zeta")]);
+        assert_eq!(sorted(vec![name("icon"), name("ICON")]), vec![name("ICON"), name("icon")]);
+    }
+
+    #[test]
+    fn numeric_groups_sort_ascending() {
+        assert_eq!(sorted(vec![GroupId::Id(101), GroupId::Id(1), GroupId::Id(32512)]),
+                   vec![GroupId::Id(1), GroupId::Id(101), GroupId::Id(32512)]);
+    }
+
+    #[test]
+    fn named_groups_come_before_numeric() {
+        assert_eq!(sorted(vec![GroupId::Id(1), name("ZZZ"), GroupId::Id(0), name("A")]),
+                   vec![name("A"), name("ZZZ"), GroupId::Id(0), GroupId::Id(1)]);
+    }
+
+    #[test]
+    fn language_preference() {
+        let select = |languages: Vec<u16>, preferred: &[u16]| main_icon(vec![(GroupId::Id(1), languages)], preferred).map(|(_, language)| language);
+        assert_eq!(select(vec![0x0000, 0x0407, 0x0409], &[]), Some(0x0000));
+        assert_eq!(select(vec![0x0407, 0x0409, 0x0411], &[]), Some(0x0409));
+        assert_eq!(select(vec![0x0407, 0x0411], &[]), Some(0x0407));
+        assert_eq!(select(vec![0x0000, 0x0407, 0x0409], &[0x0407]), Some(0x0407));
+        assert_eq!(select(vec![], &[]), None);
+    }
+
+    #[test]
+    fn multi_language_tree() {
+        let tree = vec![
+            (GroupId::Id(1), vec![0x0409]),
+            (name("MAINICON"), vec![0x0407, 0x0409]),
+            (name("APPICON"), vec![0x0411, 0x0407])
+        ];
+        assert_eq!(main_icon(tree, &[]), Some((name("APPICON"), 0x0407)));
+        let tree = vec![
+            (GroupId::Id(2), vec![0x0000]),
+            (GroupId::Id(1), vec![0x0407, 0x0409, 0x040c])
+        ];
+        assert_eq!(main_icon(tree, &[]), Some((GroupId::Id(1), 0x0409)));
+        let tree = vec![
+            (name("EMPTY"), vec![]),
+            (GroupId::Id(5), vec![0x0c0a])
+        ];
+        assert_eq!(main_icon(tree, &[]), Some((GroupId::Id(5), 0x0c0a)));
+    }
+
+    // A BITMAPINFOHEADER of an icon image, with the doubled height.
+    fn dib(width: u32, height: u32, bit_count: u16) -> Vec<u8> {
+        let mut header = vec![0u8; 40];
+        header[0..4].copy_from_slice(&40u32.to_le_bytes());
+        header[4..8].copy_from_slice(&width.to_le_bytes());
+        header[8..12].copy_from_slice(&(height * 2).to_le_bytes());
+        header[12..14].copy_from_slice(&1u16.to_le_bytes());
+        header[14..16].copy_from_slice(&bit_count.to_le_bytes());
+        header
+    }
+
+    // A 32bpp icon image with an empty AND mask.
+    fn icon_image(size: u32) -> Vec<u8> {
+        let mut image = dib(size, size, 32);
+        #[allow(clippy::manual_div_ceil)]
+        let mask = (size + 31) / 32 * 4 * size;
+        image.resize(image.len() + (size * size * 4 + mask) as usize, 0);
+        image
+    }
+
+    // A 32bpp icon image of one colour with an empty AND mask.
+    fn filled(size: u32, [red, green, blue, alpha]: [u8; 4]) -> Vec<u8> {
+        let mut image = icon_image(size);
+        for pixel in image[40..40 + (size * size * 4) as usize].chunks_exact_mut(4) {
+            pixel.copy_from_slice(&[blue, green, red, alpha]);
+        }
+        image
+    }
+
+    // A GRPICONDIR listing 32bpp images as (RT_ICON id, size).
+    fn group_dir(entries: &[(u16, u32)]) -> Vec<u8> {
+        let mut dir = [0, ico::ICON, entries.len() as u16].iter().flat_map(|value| value.to_le_bytes()).collect::<Vec<u8>>();
+        for &(id, size) in entries {
+            dir.extend_from_slice(&[size as u8, size as u8, 0, 0, 1, 0, 32, 0]);
+            dir.extend_from_slice(&icon_image(size).len().to_le_bytes()[..4]);
+            dir.extend_from_slice(&id.to_le_bytes());
+        }
+        dir
+    }
+
+    fn english(data: Vec<u8>) -> Vec<(u16, Vec<u8>)> {
+        vec![(0x0409, data)]
+    }
+
+    // Icon groups as lists of (RT_ICON id, size), and the RT_ICON images by id.
+    fn icon_pe(groups: Vec<(GroupId, Vec<(u16, u32)>)>, icons: Vec<(u16, Vec<u8>)>) -> Image {
+        let icons = icons.into_iter().map(|(id, image)| (GroupId::Id(id as u32), english(image))).collect();
+        let groups = groups.into_iter().map(|(id, entries)| (id, english(group_dir(&entries)))).collect();
+        pe(&vec![(RT_ICON as u32, icons), (RT_GROUP_ICON as u32, groups)])
+    }
+
+    // RT_CURSOR id, size and hotspot
+    type Cursor = (u16, u32, (u16, u16));
+
+    // Cursor groups and their images. Cursor directories store the doubled
+    // height of the image.
+    fn cursor_tree(groups: Vec<(GroupId, Vec<Cursor>)>) -> Tree {
+        let mut cursors = Vec::new();
+        let groups = groups.into_iter().map(|(id, entries)| {
+            let mut dir = [0, ico::CURSOR, entries.len() as u16].iter().flat_map(|value| value.to_le_bytes()).collect::<Vec<u8>>();
+            for (cursor, size, (x, y)) in entries {
+                let image = [&x.to_le_bytes()[..], &y.to_le_bytes(), &filled(size, GREEN)].concat();
+                for value in &[size as u16, size as u16 * 2, 1, 32] {
+                    dir.extend_from_slice(&value.to_le_bytes());
+                }
+                dir.extend_from_slice(&(image.len() as u32).to_le_bytes());
+                dir.extend_from_slice(&cursor.to_le_bytes());
+                cursors.push((GroupId::Id(cursor as u32), english(image)));
+            }
+            (id, english(dir))
+        }).collect();
+        vec![(RT_CURSOR as u32, cursors), (RT_GROUP_CURSOR as u32, groups)]
+    }
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const GREEN: [u8; 4] = [0, 255, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+    // A 2x2 RGBA PNG, all blue.
+    const PNG: [u8; 73] = [
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+        0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x08, 0x06, 0x00, 0x00, 0x00, 0x72, 0xb6, 0x0d,
+        0x24, 0x00, 0x00, 0x00, 0x10, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0x60, 0x60, 0xf8, 0xff,
+        0x1f, 0x82, 0xa1, 0x0c, 0x00, 0x3f, 0xd2, 0x07, 0xf9, 0x5c, 0x13, 0xe0, 0x42, 0x00, 0x00, 0x00,
+        0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82
+    ];
+
+    fn size(size: u32) -> Options {
+        Options {size, ..Options::default()}
+    }
+
+    fn reported(diagnostics: &[Diagnostic]) -> Vec<(Option<GroupId>, Option<u16>)> {
+        let mut reported: Vec<_> = diagnostics.iter().map(|diag| (diag.group.clone(), diag.entry)).collect();
+        reported.sort_by_key(|(_, entry)| *entry);
+        reported
+    }
+
+    fn key(width: u32, height: u32, bit_count: u16) -> IconKey {
+        IconKey {width, height, bit_count}
+    }
+
+    #[test]
+    fn directory_is_ranked_before_header() {
+        let (large, small) = (dib(48, 48, 32), dib(16, 16, 32));
+        // The directory has the two sizes swapped.
+        let icons = vec![(Some(key(16, 16, 32)), Ok(&large[..])), (Some(key(48, 48, 32)), Ok(&small[..]))];
+        let (ranked, reported) = rank_icons(icons.into_iter(), 16);
+        assert_eq!(ranked.iter().map(|&(index, _)| index).collect::<Vec<_>>(), [0, 1]);
+        assert!(matches!(reported[..], [(0, Error::DirectoryMismatch), (1, Error::DirectoryMismatch)]));
+        let (ranked, _) = rank_icons(vec![(None, Ok(&large[..])), (None, Ok(&small[..]))].into_iter(), 16);
+        assert_eq!(ranked[0].0, 1);
+    }
+
+    #[test]
+    fn header_fills_unknown_directory_fields() {
+        assert_eq!(resolve_key(Some(IconKey::from_directory(32, 32, 0, 0)), &dib(32, 32, 8)).unwrap(), (key(32, 32, 8), false));
+        assert_eq!(resolve_key(Some(IconKey::from_directory(0, 0, 0, 32)), &dib(512, 512, 32)).unwrap(), (key(512, 512, 32), false));
+        assert_eq!(resolve_key(Some(IconKey::from_directory(0, 0, 0, 32)), &dib(256, 256, 32)).unwrap(), (key(256, 256, 32), false));
+        assert_eq!(resolve_key(Some(IconKey::from_directory(32, 32, 16, 0)), &dib(32, 32, 4)).unwrap(), (key(32, 32, 4), false));
+    }
+
+    #[test]
+    fn mismatches_keep_the_directory_key() {
+        assert_eq!(resolve_key(Some(key(32, 32, 32)), &dib(32, 32, 8)).unwrap(), (key(32, 32, 32), true));
+        assert_eq!(resolve_key(Some(key(32, 32, 32)), &dib(24, 32, 32)).unwrap(), (key(32, 32, 32), true));
+        // An unreadable header can't disagree; decoding will report it.
+        assert_eq!(resolve_key(Some(key(32, 32, 32)), b"garbage").unwrap(), (key(32, 32, 32), false));
+        assert!(resolve_key(None, b"garbage").is_err());
+    }
+
+    #[test]
+    fn cursor_file_hotspot_follows_the_chosen_image() {
+        let (small, large) = (icon_image(16), icon_image(32));
+        // Cursor files keep the hotspot in place of planes and bit count.
+        let entry = |size: u8, (x, y): (u16, u16)| IconDirEntry {width: size, height: size, color_count: 0, planes: x, bit_count: y};
+        let images: [(IconDirEntry, &[u8]); 2] = [(entry(16, (3, 4)), &small), (entry(32, (10, 20)), &large)];
+        let mut file = ico::write_ico(&images);
+        file[2..4].copy_from_slice(&ico::CURSOR.to_le_bytes());
+        let icon = read_icon_file(&file, &Options {size: 32, ..Options::default()}).unwrap();
+        assert_eq!((icon.hotspot, icon.bit_depth), (Some((10, 20)), 32));
+        let icon = read_icon_file(&file, &Options {size: 16, ..Options::default()}).unwrap();
+        assert_eq!(icon.hotspot, Some((3, 4)));
+        file[2..4].copy_from_slice(&ico::ICON.to_le_bytes());
+        assert_eq!(read_icon_file(&file, &Options::default()).unwrap().hotspot, None);
+    }
+
+    #[test]
+    fn broken_images_fall_through_to_the_next_group() {
+        // Group 1 lists a missing image and a broken one.
+        let image = icon_pe(vec![(GroupId::Id(1), vec![(1, 32), (2, 16)]), (GroupId::Id(2), vec![(3, 16)])],
+                            vec![(2, b"broken".to_vec()), (3, filled(16, GREEN))]);
+        let icon = exelook_bytes(image.bytes(), &size(16)).unwrap();
+        assert_eq!(icon.group, GroupId::Id(2));
+        assert_eq!(icon.image.pixels[..4], GREEN);
+        assert_eq!(reported(&icon.diagnostics), [(Some(GroupId::Id(1)), Some(1)), (Some(GroupId::Id(1)), Some(2))]);
+        // Within a group the next best image is tried first.
+        let image = icon_pe(vec![(GroupId::Id(1), vec![(2, 16), (4, 32)]), (GroupId::Id(2), vec![(3, 16)])],
+                            vec![(2, b"broken".to_vec()), (3, filled(16, GREEN)), (4, filled(32, RED))]);
+        let icon = exelook_bytes(image.bytes(), &size(16)).unwrap();
+        assert_eq!((&icon.group, &icon.image.pixels[..4]), (&GroupId::Id(1), &RED[..]));
+        assert_eq!(reported(&icon.diagnostics), [(Some(GroupId::Id(1)), Some(2))]);
+    }
+
+    #[test]
+    fn indices_count_groups_in_explorer_order() {
+        let image = icon_pe(vec![(name("MAIN"), vec![(1, 16)]), (GroupId::Id(7), vec![(2, 16)]), (GroupId::Id(3), vec![(3, 16)])],
+                            vec![(1, filled(16, RED)), (2, filled(16, GREEN)), (3, filled(16, BLUE))]);
+        let bytes = image.bytes();
+        assert_eq!(icon_group_ids(bytes).unwrap(), [name("MAIN"), GroupId::Id(3), GroupId::Id(7)]);
+        assert!(cursor_group_ids(bytes).is_err());
+        let pick = |index: i32| exelook_bytes_index(bytes, index, &size(16)).map(|icon| (icon.group, icon.image.pixels[..4].to_vec()));
+        assert_eq!(pick(0).unwrap(), (name("MAIN"), RED.to_vec()));
+        assert_eq!(pick(1).unwrap(), (GroupId::Id(3), BLUE.to_vec()));
+        assert_eq!(pick(2).unwrap(), (GroupId::Id(7), GREEN.to_vec()));
+        // Negative indices are resource IDs.
+        assert_eq!(pick(-7).unwrap(), (GroupId::Id(7), GREEN.to_vec()));
+        assert_eq!(pick(-3).unwrap(), (GroupId::Id(3), BLUE.to_vec()));
+        for &index in &[3, i32::MAX, -1, -5, i32::MIN] {
+            assert!(matches!(pick(index), Err(Error::NoIconFound)), "index {}", index);
         }
-        if infoheader.compression() != 0 {
-            return Err(Error::UnknownCompression);
+    }
+
+    #[test]
+    fn icon_entries_list_every_image() {
+        let image = icon_pe(vec![(name("APP"), vec![(1, 32), (2, 2)]), (GroupId::Id(5), vec![(3, 16), (4, 16)])],
+                            vec![(1, filled(32, RED)), (2, PNG.to_vec()), (3, b"broken".to_vec())]);
+        let entries: Vec<_> = icon_entries(image.bytes()).unwrap().collect();
+        assert_eq!(entries.len(), 4);
+        let summary = |entry: &IconEntry| (entry.group.clone(), entry.language, entry.id, entry.width, entry.height, entry.bit_depth, entry.format);
+        let dib = entries[0].as_ref().unwrap();
+        assert_eq!(summary(dib), (name("APP"), 0x0409, 1, 32, 32, 32, ImageFormat::Dib));
+        assert_eq!(dib.size, icon_image(32).len());
+        let png = entries[1].as_ref().unwrap();
+        assert_eq!(summary(png), (name("APP"), 0x0409, 2, 2, 2, 32, ImageFormat::Png));
+        assert!(!dib.directory_mismatch && !png.directory_mismatch);
+        // Decoding is up to the caller.
+        let decoded = dib.decode(&Limits::default()).unwrap();
+        assert_eq!((decoded.width, decoded.height, &decoded.pixels[..4]), (32, 32, &RED[..]));
+        let decoded = png.decode(&Limits::default()).unwrap();
+        assert_eq!((decoded.width, decoded.height), (2, 2));
+        assert!(decoded.pixels.chunks(4).all(|pixel| pixel == BLUE));
+        // Entry 3 is listed but doesn't decode, entry 4 isn't there at all.
+        let broken = entries[2].as_ref().unwrap();
+        assert_eq!((&broken.group, broken.id, broken.format), (&GroupId::Id(5), 3, ImageFormat::Dib));
+        assert!(broken.decode(&Limits::default()).is_err());
+        assert!(matches!(entries[3], Err(Diagnostic {group: Some(GroupId::Id(5)), entry: Some(4), ..})));
+    }
+
+    #[test]
+    fn extracted_ico_decodes_like_the_group() {
+        // Entry 3 is missing and left out of the file.
+        let image = icon_pe(vec![(GroupId::Id(1), vec![(1, 16), (2, 2), (3, 32)])], vec![(1, filled(16, RED)), (2, PNG.to_vec())]);
+        let file = extract_ico(image.bytes(), &GroupId::Id(1), &[]).unwrap();
+        let (_, entries) = ico::read_icon_dir(&file).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.dir.width).collect::<Vec<_>>(), [16, 2]);
+        for &(target, colour) in &[(16, RED), (2, BLUE)] {
+            let from_pe = exelook_bytes(image.bytes(), &size(target)).unwrap();
+            let from_ico = read_icon_file(&file, &size(target)).unwrap();
+            assert_eq!((from_ico.image.width, from_ico.bit_depth), (target, 32));
+            assert!(from_ico.image.pixels.chunks(4).all(|pixel| pixel == colour));
+            assert_eq!(from_ico.image.pixels, from_pe.image.pixels);
+            assert!(from_ico.diagnostics.is_empty());
         }
-        let data = dib::decode_dib(best_icon)?;
-        Ok((data, false, infoheader.width(), infoheader.height() / 2))
+        assert!(matches!(extract_ico(image.bytes(), &GroupId::Id(2), &[]), Err(Error::NoIconFound)));
+    }
+
+    #[test]
+    fn cursor_entries_rank_by_their_halved_height() {
+        let entry = |size: u16, bit_count: u16| CursorDirEntry {width: size, height: size * 2, planes: 1, bit_count, id: 0};
+        assert_eq!(IconKey::from_cursor_entry(&entry(32, 32)), key(32, 32, 32));
+        let (small, large) = (icon_image(32), icon_image(48));
+        let cursors = vec![(Some(IconKey::from_cursor_entry(&entry(48, 32))), Ok(&large[..])), (Some(IconKey::from_cursor_entry(&entry(32, 32))), Ok(&small[..]))];
+        let (ranked, reported) = rank_icons(cursors.into_iter(), 32);
+        assert_eq!(ranked.iter().map(|&(index, _)| index).collect::<Vec<_>>(), [1, 0]);
+        assert!(reported.is_empty());
+    }
+
+    #[test]
+    fn cursors_are_the_fallback_without_icons() {
+        let tree = cursor_tree(vec![
+            (GroupId::Id(2), vec![(1, 16, (1, 2))]),
+            (name("ARROW"), vec![(2, 16, (3, 4)), (3, 32, (10, 20))])
+        ]);
+        let image = pe(&tree);
+        assert_eq!(cursor_group_ids(image.bytes()).unwrap(), [name("ARROW"), GroupId::Id(2)]);
+        // Named groups come first, and the hotspot is the chosen image's.
+        let cursor = exelook_bytes(image.bytes(), &size(32)).unwrap();
+        assert_eq!((&cursor.group, cursor.hotspot, cursor.image.width), (&name("ARROW"), Some((10, 20)), 32));
+        assert_eq!(cursor.image.pixels[..4], GREEN);
+        let cursor = exelook_bytes(image.bytes(), &size(16)).unwrap();
+        assert_eq!((cursor.hotspot, cursor.image.width), (Some((3, 4)), 16));
+        // Any icon group wins over cursors.
+        let mut tree = tree;
+        tree.extend(vec![
+            (RT_ICON as u32, vec![(GroupId::Id(9), english(filled(16, RED)))]),
+            (RT_GROUP_ICON as u32, vec![(GroupId::Id(1), english(group_dir(&[(9, 16)])))])
+        ]);
+        let icon = exelook_bytes(pe(&tree).bytes(), &size(16)).unwrap();
+        assert_eq!((icon.group, icon.hotspot, &icon.image.pixels[..4]), (GroupId::Id(1), None, &RED[..]));
+    }
+
+    #[test]
+    fn broken_cursor_groups_fall_through() {
+        let mut tree = cursor_tree(vec![(name("ARROW"), vec![(1, 16, (1, 1))]), (GroupId::Id(7), vec![(2, 16, (5, 6))])]);
+        // Cut the first cursor down to its hotspot.
+        tree[0].1[0].1[0].1.truncate(4);
+        let cursor = exelook_bytes(pe(&tree).bytes(), &size(16)).unwrap();
+        assert_eq!((cursor.group, cursor.hotspot), (GroupId::Id(7), Some((5, 6))));
+        assert_eq!(reported(&cursor.diagnostics), [(Some(name("ARROW")), Some(1))]);
+    }
+
+    #[test]
+    fn embedded_ani_errors_are_reported() {
+        // An animated cursor whose frames aren't icons
+        let mut ani = [&b"RIFF"[..], &48u32.to_le_bytes(), b"ACONanih", &36u32.to_le_bytes()].concat();
+        ani.extend_from_slice(&[0; 36]);
+        let image = pe(&vec![(RT_ANICURSOR as u32, vec![(GroupId::Id(3), english(ani))])]);
+        assert!(matches!(exelook_bytes(image.bytes(), &size(32)), Err(Error::UnsupportedAni)));
     }
 }
@@ -0,0 +1,198 @@
+use std::convert::TryInto;
+
+use crate::exelook::{Error, Result};
+
+pub const ICONDIR_SIZE: usize = 6;
+pub const ICONDIRENTRY_SIZE: usize = 16;
+pub const GRPICONDIRENTRY_SIZE: usize = 14;
+pub const GRPCURSORDIRENTRY_SIZE: usize = 14;
+pub const ICON: u16 = 1;
+pub const CURSOR: u16 = 2;
+
+// The fields an ICONDIRENTRY shares with a GRPICONDIRENTRY; the image size and
+// offset are filled in from the image bytes when writing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IconDirEntry {
+    pub width: u8,
+    pub height: u8,
+    pub color_count: u8,
+    pub planes: u16,
+    pub bit_count: u16
+}
+
+// In .cur files planes and bit_count hold the hotspot instead.
+impl IconDirEntry {
+    pub fn hotspot(&self) -> (u16, u16) {
+        (self.planes, self.bit_count)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IconFileEntry {
+    pub dir: IconDirEntry,
+    pub size: u32,
+    pub offset: u32
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn is_icon_file(bytes: &[u8]) -> bool {
+    bytes.len() >= ICONDIR_SIZE && u16_at(bytes, 0) == 0
+        && (u16_at(bytes, 2) == ICON || u16_at(bytes, 2) == CURSOR) && u16_at(bytes, 4) != 0
+}
+
+pub fn read_icon_dir(bytes: &[u8]) -> Result<(u16, Vec<IconFileEntry>)> {
+    if !is_icon_file(bytes) {
+        return Err(Error::MalformedIconFile);
+    }
+    let count = u16_at(bytes, 4) as usize;
+    if bytes.len() < ICONDIR_SIZE + count * ICONDIRENTRY_SIZE {
+        return Err(Error::MalformedIconFile);
+    }
+    let entries = bytes[ICONDIR_SIZE..ICONDIR_SIZE + count * ICONDIRENTRY_SIZE].chunks_exact(ICONDIRENTRY_SIZE).map(|entry| {
+        IconFileEntry {
+            dir: IconDirEntry {
+                width: entry[0],
+                height: entry[1],
+                color_count: entry[2],
+                planes: u16_at(entry, 4),
+                bit_count: u16_at(entry, 6)
+            },
+            size: u32_at(entry, 8),
+            offset: u32_at(entry, 12)
+        }
+    }).collect();
+    Ok((u16_at(bytes, 2), entries))
+}
+
+pub fn image<'a>(bytes: &'a [u8], entry: &IconFileEntry) -> Result<&'a [u8]> {
+    let start = entry.offset as usize;
+    let end = start.checked_add(entry.size as usize).ok_or(Error::MalformedIconFile)?;
+    bytes.get(start..end).ok_or(Error::MalformedIconFile)
+}
+
+// A GRPICONDIRENTRY for resource formats pelite doesn't handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupDirEntry {
+    pub dir: IconDirEntry,
+    pub size: u32,
+    pub id: u16
+}
+
+// Trailing bytes are allowed, NE resources are padded to their alignment.
+pub fn read_icon_group(bytes: &[u8]) -> Result<Vec<GroupDirEntry>> {
+    if bytes.len() < ICONDIR_SIZE || u16_at(bytes, 0) != 0 || u16_at(bytes, 2) != ICON {
+        return Err(Error::MalformedIconFile);
+    }
+    let count = u16_at(bytes, 4) as usize;
+    let entries = bytes.get(ICONDIR_SIZE..ICONDIR_SIZE + count * GRPICONDIRENTRY_SIZE).ok_or(Error::MalformedIconFile)?;
+    Ok(entries.chunks_exact(GRPICONDIRENTRY_SIZE).map(|entry| {
+        GroupDirEntry {
+            dir: IconDirEntry {
+                width: entry[0],
+                height: entry[1],
+                color_count: entry[2],
+                planes: u16_at(entry, 4),
+                bit_count: u16_at(entry, 6)
+            },
+            size: u32_at(entry, 8),
+            id: u16_at(entry, 12)
+        }
+    }).collect())
+}
+
+// RT_GROUP_CURSOR entries have 16-bit dimensions instead of the icon layout,
+// and the height covers both the XOR and AND masks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorDirEntry {
+    pub width: u16,
+    pub height: u16,
+    pub planes: u16,
+    pub bit_count: u16,
+    pub id: u16
+}
+
+pub fn read_cursor_group(bytes: &[u8]) -> Result<Vec<CursorDirEntry>> {
+    if bytes.len() < ICONDIR_SIZE || u16_at(bytes, 0) != 0 || u16_at(bytes, 2) != CURSOR {
+        return Err(Error::MalformedCursor);
+    }
+    let count = u16_at(bytes, 4) as usize;
+    let entries = bytes.get(ICONDIR_SIZE..ICONDIR_SIZE + count * GRPCURSORDIRENTRY_SIZE).ok_or(Error::MalformedCursor)?;
+    Ok(entries.chunks_exact(GRPCURSORDIRENTRY_SIZE).map(|entry| {
+        CursorDirEntry {
+            width: u16_at(entry, 0),
+            height: u16_at(entry, 2),
+            planes: u16_at(entry, 4),
+            bit_count: u16_at(entry, 6),
+            id: u16_at(entry, 12)
+        }
+    }).collect())
+}
+
+// RT_CURSOR data is the image prefixed by the hotspot.
+pub fn split_hotspot(bytes: &[u8]) -> Result<((u16, u16), &[u8])> {
+    if bytes.len() < 4 {
+        return Err(Error::MalformedCursor);
+    }
+    Ok(((u16_at(bytes, 0), u16_at(bytes, 2)), &bytes[4..]))
+}
+
+// The group directory is copied entry by entry with the 2-byte nId turned into a
+// 4-byte file offset. dwBytesInRes is taken from the image itself, resource
+// compilers don't always get it right.
+pub fn write_ico(images: &[(IconDirEntry, &[u8])]) -> Vec<u8> {
+    let header_size = ICONDIR_SIZE + images.len() * ICONDIRENTRY_SIZE;
+    let mut out = Vec::with_capacity(header_size + images.iter().map(|(_, bytes)| bytes.len()).sum::<usize>());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out.extend_from_slice(&ICON.to_le_bytes());
+    out.extend_from_slice(&(images.len() as u16).to_le_bytes());
+    let mut offset = header_size as u32;
+    for (entry, bytes) in images {
+        out.extend_from_slice(&[entry.width, entry.height, entry.color_count, 0]);
+        out.extend_from_slice(&entry.planes.to_le_bytes());
+        out.extend_from_slice(&entry.bit_count.to_le_bytes());
+        out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        offset += bytes.len() as u32;
+    }
+    for (_, bytes) in images {
+        out.extend_from_slice(bytes);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_round_trips() {
+        let small = IconDirEntry {width: 16, height: 16, color_count: 16, planes: 1, bit_count: 4};
+        let large = IconDirEntry {width: 0, height: 0, color_count: 0, planes: 1, bit_count: 32};
+        let images: [(IconDirEntry, &[u8]); 2] = [(small, b"first image"), (large, b"\x89PNG second")];
+        let file = write_ico(&images);
+        let (kind, entries) = read_icon_dir(&file).unwrap();
+        assert_eq!(kind, ICON);
+        assert_eq!(entries.len(), 2);
+        for ((dir, bytes), entry) in images.iter().zip(&entries) {
+            assert_eq!(entry.dir, *dir);
+            assert_eq!(entry.size as usize, bytes.len());
+            assert_eq!(image(&file, entry).unwrap(), *bytes);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let file = write_ico(&[(IconDirEntry {width: 16, height: 16, color_count: 0, planes: 1, bit_count: 32}, b"image")]);
+        assert!(read_icon_dir(&file[..10]).is_err());
+        let (_, entries) = read_icon_dir(&file[..file.len() - 1]).unwrap();
+        assert!(image(&file[..file.len() - 1], &entries[0]).is_err());
+        assert!(!is_icon_file(b"MZ\x90\x00\x03\x00"));
+    }
+}
@@ -0,0 +1,255 @@
+use crate::{
+    bytes::{u16_at, u32_at},
+    exelook::{Error, Result}
+};
+
+const HEADER_SIZE: usize = 0x4c;
+const LINK_CLSID: &[u8] = b"\x01\x14\x02\x00\x00\x00\x00\x00\xc0\x00\x00\x00\x00\x00\x00\x46";
+
+// LinkFlags
+const HAS_LINK_TARGET_ID_LIST: u32 = 0x1;
+const HAS_LINK_INFO: u32 = 0x2;
+const HAS_NAME: u32 = 0x4;
+const HAS_RELATIVE_PATH: u32 = 0x8;
+const HAS_WORKING_DIR: u32 = 0x10;
+const HAS_ARGUMENTS: u32 = 0x20;
+const HAS_ICON_LOCATION: u32 = 0x40;
+const IS_UNICODE: u32 = 0x80;
+const FORCE_NO_LINK_INFO: u32 = 0x100;
+const HAS_EXP_STRING: u32 = 0x200;
+const HAS_EXP_ICON: u32 = 0x4000;
+
+// LinkInfoFlags
+const VOLUME_ID_AND_LOCAL_BASE_PATH: u32 = 0x1;
+const COMMON_NETWORK_RELATIVE_LINK_AND_PATH_SUFFIX: u32 = 0x2;
+
+const ENVIRONMENT_PROPS: u32 = 0xa000_0001;
+const ICON_ENVIRONMENT_PROPS: u32 = 0xa000_0007;
+const EXTENSION_BLOCK: u32 = 0xbeef_0004;
+
+// Every path is a Windows path as stored in the link; environment variables in
+// the two environment blocks are left unexpanded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShellLink {
+    pub flags: u32,
+    pub icon_index: i32,
+    // Rebuilt from drive and file system items only.
+    pub id_list_path: Option<String>,
+    pub local_path: Option<String>,
+    pub network_path: Option<String>,
+    pub name: Option<String>,
+    pub relative_path: Option<String>,
+    pub working_dir: Option<String>,
+    pub arguments: Option<String>,
+    pub icon_location: Option<String>,
+    pub environment_target: Option<String>,
+    pub icon_environment: Option<String>
+}
+
+// The codepage of ANSI strings isn't recorded, so they are read as Latin-1.
+fn ansi(bytes: &[u8]) -> String {
+    bytes.iter().map(|&c| c as char).collect()
+}
+
+fn utf16(units: impl Iterator<Item = u16>) -> String {
+    std::char::decode_utf16(units).map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER)).collect()
+}
+
+fn ansi_z(bytes: &[u8], offset: usize) -> Result<String> {
+    let tail = bytes.get(offset..).ok_or(Error::MalformedLnk)?;
+    Ok(ansi(&tail[..tail.iter().position(|&c| c == 0).unwrap_or(tail.len())]))
+}
+
+fn utf16_z(bytes: &[u8], offset: usize) -> Result<String> {
+    let tail = bytes.get(offset..).ok_or(Error::MalformedLnk)?;
+    Ok(utf16(tail.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).take_while(|&c| c != 0)))
+}
+
+fn non_empty(string: String) -> Option<String> {
+    if string.is_empty() {None} else {Some(string)}
+}
+
+pub fn is_lnk(bytes: &[u8]) -> bool {
+    bytes.len() >= HEADER_SIZE && u32_at(bytes, 0, Error::MalformedLnk).ok() == Some(HEADER_SIZE as u32) && &bytes[4..20] == LINK_CLSID
+}
+
+// A file system item keeps its 8.3 name inline and the long name in a trailing
+// 0xbeef0004 extension block whose layout grew with every Windows version.
+fn file_item_name(item: &[u8]) -> Result<String> {
+    let short = ansi_z(item, 12)?;
+    // The offset at the end counts from the item's size field, which isn't part
+    // of item.
+    let extension = item.len().checked_sub(2).and_then(|end| u16_at(item, end, Error::MalformedLnk).ok()).map_or(0, |offset| offset as usize).saturating_sub(2);
+    if extension < 12 || u32_at(item, extension + 4, Error::MalformedLnk).ok() != Some(EXTENSION_BLOCK) {
+        return Ok(short);
+    }
+    let version = u16_at(item, extension + 2, Error::MalformedLnk)?;
+    let mut name = 18;
+    if version >= 7 {
+        name += 18;
+    }
+    if version >= 3 {
+        name += 2;
+    }
+    if version >= 9 {
+        name += 4;
+    }
+    if version >= 8 {
+        name += 4;
+    }
+    Ok(non_empty(utf16_z(item, extension + name)?).unwrap_or(short))
+}
+
+// Only the common case of a drive followed by folders and a file is turned into
+// a path; anything else (control panel items, URIs, ...) gives None.
+fn id_list_path(list: &[u8]) -> Result<Option<String>> {
+    let mut path: Option<String> = None;
+    let mut pos = 0;
+    loop {
+        let size = u16_at(list, pos, Error::MalformedLnk)? as usize;
+        if size == 0 {
+            break;
+        }
+        let item = list.get(pos + 2..pos + size).ok_or(Error::MalformedLnk)?;
+        pos += size;
+        match item.first().map(|kind| kind & 0x70) {
+            // The root folder (usually My Computer) contributes nothing.
+            _ if item.first() == Some(&0x1f) => {},
+            Some(0x20) => path = Some(ansi_z(item, 1)?),
+            Some(0x30) => match &mut path {
+                Some(path) => {
+                    if !path.ends_with('\\') {
+                        path.push('\\');
+                    }
+                    path.push_str(&file_item_name(item)?);
+                },
+                None => return Ok(None)
+            },
+            _ => return Ok(None)
+        }
+    }
+    Ok(path)
+}
+
+fn read_link_info(link: &mut ShellLink, info: &[u8]) -> Result<()> {
+    let header_size = u32_at(info, 4, Error::MalformedLnk)?;
+    let flags = u32_at(info, 8, Error::MalformedLnk)?;
+    let unicode = header_size >= 0x24;
+    let string = |ansi_offset: usize, unicode_offset: usize| -> Result<String> {
+        if unicode && u32_at(info, unicode_offset, Error::MalformedLnk)? != 0 {
+            utf16_z(info, u32_at(info, unicode_offset, Error::MalformedLnk)? as usize)
+        } else {
+            ansi_z(info, u32_at(info, ansi_offset, Error::MalformedLnk)? as usize)
+        }
+    };
+    let suffix = string(24, 32)?;
+    if flags & VOLUME_ID_AND_LOCAL_BASE_PATH != 0 {
+        link.local_path = non_empty(string(16, 28)? + &suffix);
+    }
+    if flags & COMMON_NETWORK_RELATIVE_LINK_AND_PATH_SUFFIX != 0 {
+        let network = info.get(u32_at(info, 20, Error::MalformedLnk)? as usize..).ok_or(Error::MalformedLnk)?;
+        let net_name_offset = u32_at(network, 8, Error::MalformedLnk)?;
+        let mut path = if net_name_offset > 0x14 {utf16_z(network, u32_at(network, 20, Error::MalformedLnk)? as usize)?} else {ansi_z(network, net_name_offset as usize)?};
+        if !suffix.is_empty() {
+            if !path.ends_with('\\') {
+                path.push('\\');
+            }
+            path.push_str(&suffix);
+        }
+        link.network_path = non_empty(path);
+    }
+    Ok(())
+}
+
+// EnvironmentVariableDataBlock and IconEnvironmentDataBlock share a layout: a
+// 260 byte ANSI path followed by a 520 byte Unicode one, either may be empty.
+// A block too short for both only loses its own path, like a truncated tail.
+fn environment_path(block: &[u8]) -> Option<String> {
+    let target = block.get(8..8 + 260)?;
+    let target_unicode = block.get(8 + 260..8 + 260 + 520)?;
+    utf16_z(target_unicode, 0).ok().and_then(non_empty).or_else(|| non_empty(ansi_z(target, 0).ok()?))
+}
+
+pub fn parse_lnk(bytes: &[u8]) -> Result<ShellLink> {
+    if !is_lnk(bytes) {
+        return Err(Error::MalformedLnk);
+    }
+    let flags = u32_at(bytes, 0x14, Error::MalformedLnk)?;
+    let mut link = ShellLink {flags, icon_index: u32_at(bytes, 0x38, Error::MalformedLnk)? as i32, ..ShellLink::default()};
+    let mut pos = HEADER_SIZE;
+    if flags & HAS_LINK_TARGET_ID_LIST != 0 {
+        let size = u16_at(bytes, pos, Error::MalformedLnk)? as usize;
+        let list = bytes.get(pos + 2..pos + 2 + size).ok_or(Error::MalformedLnk)?;
+        link.id_list_path = id_list_path(list).unwrap_or(None);
+        pos += 2 + size;
+    }
+    if flags & HAS_LINK_INFO != 0 {
+        let size = u32_at(bytes, pos, Error::MalformedLnk)? as usize;
+        let info = bytes.get(pos..pos.checked_add(size).ok_or(Error::MalformedLnk)?).ok_or(Error::MalformedLnk)?;
+        // A damaged LinkInfo only costs the paths it would have provided.
+        if flags & FORCE_NO_LINK_INFO == 0 && read_link_info(&mut link, info).is_err() {
+            link.local_path = None;
+            link.network_path = None;
+        }
+        pos += size;
+    }
+    // StringData: a character count, then UTF-16 or ANSI characters without a
+    // terminator, for every flag that is set, in this order.
+    let strings = [HAS_NAME, HAS_RELATIVE_PATH, HAS_WORKING_DIR, HAS_ARGUMENTS, HAS_ICON_LOCATION];
+    for (index, &flag) in strings.iter().enumerate() {
+        if flags & flag == 0 {
+            continue;
+        }
+        let count = u16_at(bytes, pos, Error::MalformedLnk)? as usize;
+        let size = if flags & IS_UNICODE != 0 {count * 2} else {count};
+        let data = bytes.get(pos + 2..pos + 2 + size).ok_or(Error::MalformedLnk)?;
+        let string = if flags & IS_UNICODE != 0 {
+            utf16(data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])))
+        } else {
+            ansi(data)
+        };
+        let string = non_empty(string);
+        match index {
+            0 => link.name = string,
+            1 => link.relative_path = string,
+            2 => link.working_dir = string,
+            3 => link.arguments = string,
+            _ => link.icon_location = string
+        }
+        pos += 2 + size;
+    }
+    // ExtraData blocks run until one smaller than 4 bytes; a truncated tail is
+    // ignored, the link itself is still usable.
+    while let Ok(size) = u32_at(bytes, pos, Error::MalformedLnk) {
+        let size = size as usize;
+        let block = match bytes.get(pos..pos.saturating_add(size)) {
+            Some(block) if size >= 8 => block,
+            _ => break
+        };
+        match u32_at(block, 4, Error::MalformedLnk)? {
+            ENVIRONMENT_PROPS if flags & HAS_EXP_STRING != 0 => link.environment_target = environment_path(block),
+            ICON_ENVIRONMENT_PROPS if flags & HAS_EXP_ICON != 0 => link.icon_environment = environment_path(block),
+            _ => {}
+        }
+        pos += size;
+    }
+    Ok(link)
+}
+
+impl ShellLink {
+    // Where the shell would look for the icon, best first: the explicit icon
+    // location (the environment block version wins) with the link's icon
+    // index, then the target itself with index 0.
+    pub fn icon_candidates(&self) -> Vec<(&str, i32)> {
+        let mut candidates = Vec::new();
+        for location in self.icon_environment.iter().chain(&self.icon_location) {
+            candidates.push((location.as_str(), self.icon_index));
+        }
+        let targets = [&self.environment_target, &self.local_path, &self.network_path, &self.id_list_path, &self.relative_path];
+        for target in targets.iter().filter_map(|target| target.as_ref()) {
+            candidates.push((target.as_str(), 0));
+        }
+        candidates.dedup();
+        candidates
+    }
+}
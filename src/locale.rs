@@ -0,0 +1,113 @@
+use std::env;
+
+pub const LANG_NEUTRAL: u16 = 0x0000;
+pub const LANG_EN_US: u16 = 0x0409;
+
+// The first entry for every language is its SUBLANG_DEFAULT variant, which is
+// what a bare language code or an unknown region maps to.
+const LOCALES: &[(&str, u16)] = &[
+    ("en-us", 0x0409), ("en-gb", 0x0809), ("en-au", 0x0c09), ("en-ca", 0x1009), ("en-nz", 0x1409), ("en-ie", 0x1809),
+    ("de-de", 0x0407), ("de-ch", 0x0807), ("de-at", 0x0c07), ("de-lu", 0x1007), ("de-li", 0x1407),
+    ("fr-fr", 0x040c), ("fr-be", 0x080c), ("fr-ca", 0x0c0c), ("fr-ch", 0x100c), ("fr-lu", 0x140c),
+    ("es-es", 0x0c0a), ("es-mx", 0x080a), ("es-ar", 0x2c0a), ("es-co", 0x240a), ("es-cl", 0x340a),
+    ("it-it", 0x0410), ("it-ch", 0x0810),
+    ("pt-br", 0x0416), ("pt-pt", 0x0816),
+    ("nl-nl", 0x0413), ("nl-be", 0x0813),
+    ("sv-se", 0x041d), ("sv-fi", 0x081d),
+    ("zh-tw", 0x0404), ("zh-cn", 0x0804), ("zh-hk", 0x0c04), ("zh-sg", 0x1004), ("zh-mo", 0x1404),
+    ("ar-sa", 0x0401), ("bg-bg", 0x0402), ("ca-es", 0x0403), ("cs-cz", 0x0405), ("da-dk", 0x0406),
+    ("el-gr", 0x0408), ("fi-fi", 0x040b), ("he-il", 0x040d), ("hu-hu", 0x040e), ("is-is", 0x040f),
+    ("ja-jp", 0x0411), ("ko-kr", 0x0412), ("nb-no", 0x0414), ("pl-pl", 0x0415), ("ro-ro", 0x0418),
+    ("ru-ru", 0x0419), ("hr-hr", 0x041a), ("sk-sk", 0x041b), ("sq-al", 0x041c), ("th-th", 0x041e),
+    ("tr-tr", 0x041f), ("ur-pk", 0x0420), ("id-id", 0x0421), ("uk-ua", 0x0422), ("be-by", 0x0423),
+    ("sl-si", 0x0424), ("et-ee", 0x0425), ("lv-lv", 0x0426), ("lt-lt", 0x0427), ("fa-ir", 0x0429),
+    ("vi-vn", 0x042a), ("hy-am", 0x042b), ("eu-es", 0x042d), ("mk-mk", 0x042f), ("af-za", 0x0436),
+    ("ka-ge", 0x0437), ("hi-in", 0x0439), ("ms-my", 0x043e), ("kk-kz", 0x043f), ("sw-ke", 0x0441),
+    ("bn-in", 0x0445), ("ta-in", 0x0449), ("te-in", 0x044a), ("mr-in", 0x044e), ("gl-es", 0x0456),
+    ("sr-rs", 0x241a)
+];
+
+fn primary(lang: u16) -> u16 {
+    lang & 0x3ff
+}
+
+// Accepts BCP 47 tags ("de-AT") as well as POSIX locales ("de_AT.UTF-8@euro").
+pub fn langid_from_locale(locale: &str) -> Option<u16> {
+    let tag = locale.split(&['.', '@'][..]).next()?.replace('_', "-").to_lowercase();
+    let mut parts = tag.split('-');
+    let language = parts.next().filter(|language| !language.is_empty())?;
+    let region = parts.next_back();
+    let mut candidates = LOCALES.iter().filter(|(name, _)| name.split('-').next() == Some(language));
+    let default = candidates.clone().next()?.1;
+    Some(region.and_then(|region| candidates.find(|(name, _)| name.split('-').nth(1) == Some(region)).map(|&(_, id)| id)).unwrap_or(default))
+}
+
+// LANGUAGE is a colon separated preference list, the others hold a single
+// locale; C/POSIX carry no language preference.
+pub fn languages_from_env() -> Vec<u16> {
+    let mut languages = Vec::new();
+    for var in &["LANGUAGE", "LC_ALL", "LC_MESSAGES", "LANG"] {
+        if let Ok(value) = env::var(var) {
+            for locale in value.split(':') {
+                if let Some(id) = langid_from_locale(locale) {
+                    if !languages.contains(&id) {
+                        languages.push(id);
+                    }
+                }
+            }
+        }
+    }
+    languages
+}
+
+// The Windows resource loader fallback: for every preferred language an exact
+// match, then the same primary language (preferring its default sublanguage),
+// then language neutral, en-US and finally the lowest available ID.
+pub fn select_language(available: impl Iterator<Item = u16>, preferred: &[u16]) -> Option<u16> {
+    let available: Vec<u16> = available.collect();
+    for &lang in preferred {
+        if available.contains(&lang) {
+            return Some(lang);
+        }
+        let same_primary = available.iter().filter(|&&id| primary(id) == primary(lang) && primary(lang) != LANG_NEUTRAL);
+        if let Some(&id) = same_primary.min_by_key(|&&id| (id >> 10 != 1, id)) {
+            return Some(id);
+        }
+    }
+    available.iter().cloned().min_by_key(|&lang| match lang {
+        LANG_NEUTRAL => (0, lang),
+        _ if primary(lang) == LANG_NEUTRAL => (1, lang),
+        LANG_EN_US => (2, lang),
+        _ => (3, lang)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_locales() {
+        assert_eq!(langid_from_locale("de_AT.UTF-8@euro"), Some(0x0c07));
+        assert_eq!(langid_from_locale("pt-BR"), Some(0x0416));
+        assert_eq!(langid_from_locale("de"), Some(0x0407));
+        assert_eq!(langid_from_locale("fr_BF"), Some(0x040c));
+        assert_eq!(langid_from_locale("zh-Hant-TW"), Some(0x0404));
+        assert_eq!(langid_from_locale("C"), None);
+        assert_eq!(langid_from_locale(""), None);
+    }
+
+    #[test]
+    fn fallback_chain() {
+        let available = [0x0000, 0x0407, 0x0807, 0x0409, 0x0411];
+        let select = |preferred: &[u16]| select_language(available.iter().cloned(), preferred);
+        assert_eq!(select(&[0x0807]), Some(0x0807));
+        assert_eq!(select(&[0x0c07]), Some(0x0407));
+        assert_eq!(select(&[0x040c, 0x0411]), Some(0x0411));
+        assert_eq!(select(&[0x040c]), Some(0x0000));
+        assert_eq!(select_language([0x0411, 0x0409].iter().cloned(), &[0x040c]), Some(0x0409));
+        assert_eq!(select_language([0x0411, 0x0407].iter().cloned(), &[0x040c]), Some(0x0407));
+        assert_eq!(select_language([0x0c0a, 0x080a].iter().cloned(), &[0x040a]), Some(0x080a));
+        assert_eq!(select_language(std::iter::empty(), &[0x0409]), None);
+    }
+}
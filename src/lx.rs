@@ -0,0 +1,191 @@
+use crate::{
+    bytes::{u16_at, u32_at},
+    exelook::{Error, Limits, Result}
+};
+
+pub const RT_POINTER: u16 = 1;
+pub const RT_BITMAP: u16 = 2;
+
+// Relative to the LX/LE header, except e32_datapage which is a file offset.
+const E32_MPAGES: usize = 0x14;
+const E32_PAGESIZE: usize = 0x28;
+const E32_PAGESHIFT: usize = 0x2c;
+const E32_OBJTAB: usize = 0x40;
+const E32_OBJCNT: usize = 0x44;
+const E32_OBJMAP: usize = 0x48;
+const E32_RSRCTAB: usize = 0x50;
+const E32_RSRCCNT: usize = 0x54;
+const E32_DATAPAGE: usize = 0x80;
+const OBJECT_SIZE: usize = 24;
+const RESOURCE_SIZE: usize = 14;
+
+const PAGE_VALID: u16 = 0;
+const PAGE_ITERATED: u16 = 1;
+const PAGE_INVALID: u16 = 2;
+const PAGE_ZEROED: u16 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LxResource {
+    pub kind: u16,
+    pub id: u16,
+    pub size: u32,
+    pub object: u16,
+    pub offset: u32
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Object {
+    page_map: u32,
+    page_count: u32
+}
+
+// Linear executables: OS/2 2.x LX and the older LE used by Windows 9x VxDs. They
+// share the header and tables and differ in the object page map layout.
+pub struct LxFile<'a> {
+    bytes: &'a [u8],
+    le: bool,
+    page_size: usize,
+    // e32_pageshift for LX, the size of the last page for LE.
+    page_shift: u32,
+    pages: u32,
+    page_map: usize,
+    data_pages: usize,
+    objects: Vec<Object>,
+    pub resources: Vec<LxResource>
+}
+
+// Either behind an MZ stub or, for some OS/2 files, at the very start.
+fn lx_header(bytes: &[u8]) -> Option<(usize, bool)> {
+    let header = if bytes.starts_with(b"MZ") {u32_at(bytes, 0x3c, Error::MalformedLx).ok()? as usize} else {0};
+    match bytes.get(header..header.checked_add(2)?) {
+        Some(b"LX") => Some((header, false)),
+        Some(b"LE") => Some((header, true)),
+        _ => None
+    }
+}
+
+pub fn is_lx(bytes: &[u8]) -> bool {
+    lx_header(bytes).is_some()
+}
+
+// EXEPACK1: records of a repeat count, a length and that many bytes to repeat.
+fn unpack_iterated(src: &[u8], page: &mut [u8]) -> Result<()> {
+    let mut pos = 0;
+    let mut out = 0;
+    while pos + 4 <= src.len() && out < page.len() {
+        let count = u16_at(src, pos, Error::MalformedLx)? as usize;
+        let len = u16_at(src, pos + 2, Error::MalformedLx)? as usize;
+        let data = src.get(pos + 4..pos + 4 + len).ok_or(Error::MalformedLx)?;
+        pos += 4 + len;
+        if len == 0 {
+            continue;
+        }
+        for _ in 0..count {
+            let end = (out + len).min(page.len());
+            page[out..end].copy_from_slice(&data[..end - out]);
+            out = end;
+        }
+    }
+    Ok(())
+}
+
+impl<'a> LxFile<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<LxFile<'a>> {
+        let (header, le) = lx_header(bytes).ok_or(Error::MalformedLx)?;
+        // Byte and word order; big endian images only exist on paper.
+        if u16_at(bytes, header + 2, Error::MalformedLx)? != 0 {
+            return Err(Error::UnsupportedLx);
+        }
+        let page_size = u32_at(bytes, header + E32_PAGESIZE, Error::MalformedLx)? as usize;
+        let page_shift = u32_at(bytes, header + E32_PAGESHIFT, Error::MalformedLx)?;
+        if page_size == 0 || page_size > 0x10000 || (!le && page_shift > 24) {
+            return Err(Error::MalformedLx);
+        }
+        let object_table = header + u32_at(bytes, header + E32_OBJTAB, Error::MalformedLx)? as usize;
+        let object_count = u32_at(bytes, header + E32_OBJCNT, Error::MalformedLx)? as usize;
+        let objects = (0..object_count.min(bytes.len() / OBJECT_SIZE)).map(|index| {
+            let entry = object_table + index * OBJECT_SIZE;
+            Ok(Object {page_map: u32_at(bytes, entry + 12, Error::MalformedLx)?, page_count: u32_at(bytes, entry + 16, Error::MalformedLx)?})
+        }).collect::<Result<Vec<_>>>()?;
+        let resource_table = header + u32_at(bytes, header + E32_RSRCTAB, Error::MalformedLx)? as usize;
+        let resource_count = u32_at(bytes, header + E32_RSRCCNT, Error::MalformedLx)? as usize;
+        let resources = (0..resource_count.min(bytes.len() / RESOURCE_SIZE)).map(|index| {
+            let entry = resource_table + index * RESOURCE_SIZE;
+            Ok(LxResource {
+                kind: u16_at(bytes, entry, Error::MalformedLx)?,
+                id: u16_at(bytes, entry + 2, Error::MalformedLx)?,
+                size: u32_at(bytes, entry + 4, Error::MalformedLx)?,
+                object: u16_at(bytes, entry + 8, Error::MalformedLx)?,
+                offset: u32_at(bytes, entry + 10, Error::MalformedLx)?
+            })
+        }).collect::<Result<Vec<_>>>()?;
+        Ok(LxFile {
+            bytes,
+            le,
+            page_size,
+            page_shift,
+            pages: u32_at(bytes, header + E32_MPAGES, Error::MalformedLx)?,
+            page_map: header + u32_at(bytes, header + E32_OBJMAP, Error::MalformedLx)? as usize,
+            data_pages: u32_at(bytes, header + E32_DATAPAGE, Error::MalformedLx)? as usize,
+            objects,
+            resources
+        })
+    }
+
+    // page is the 1-based index into the object page map.
+    fn read_page(&self, page: u32, out: &mut [u8]) -> Result<()> {
+        let index = (page as usize).checked_sub(1).ok_or(Error::MalformedLx)?;
+        let entry = |entry_size: usize| index.checked_mul(entry_size)
+            .and_then(|offset| self.page_map.checked_add(offset)).ok_or(Error::MalformedLx);
+        let (offset, size, flags) = if self.le {
+            let entry = entry(4)?;
+            let raw = entry.checked_add(4).and_then(|end| self.bytes.get(entry..end)).ok_or(Error::MalformedLx)?;
+            let number = (raw[0] as usize) << 16 | (raw[1] as usize) << 8 | raw[2] as usize;
+            let size = if number as u32 == self.pages {self.page_shift as usize} else {self.page_size};
+            let offset = number.checked_sub(1).and_then(|number| number.checked_mul(self.page_size)).ok_or(Error::MalformedLx)?;
+            (offset, size, raw[3] as u16)
+        } else {
+            let entry = entry(8)?;
+            let unit = 1usize.checked_shl(self.page_shift).ok_or(Error::MalformedLx)?;
+            let offset = (u32_at(self.bytes, entry, Error::MalformedLx)? as usize).checked_mul(unit).ok_or(Error::MalformedLx)?;
+            (offset, u16_at(self.bytes, entry + 4, Error::MalformedLx)? as usize, u16_at(self.bytes, entry + 6, Error::MalformedLx)?)
+        };
+        let start = self.data_pages.checked_add(offset).ok_or(Error::MalformedLx)?;
+        let data = || self.bytes.get(start..start.saturating_add(size.min(self.page_size))).ok_or(Error::MalformedLx);
+        match flags {
+            PAGE_VALID => {
+                let data = data()?;
+                out[..data.len()].copy_from_slice(data);
+            },
+            PAGE_ITERATED => unpack_iterated(data()?, out)?,
+            PAGE_INVALID | PAGE_ZEROED => {},
+            // EXEPACK2 and anything newer
+            _ => return Err(Error::UnsupportedLx)
+        }
+        Ok(())
+    }
+
+    // Resources live inside an object, which may span several pages and be
+    // longer than the pages actually stored in the file (the rest is zero).
+    pub fn read(&self, resource: &LxResource, limits: &Limits) -> Result<Vec<u8>> {
+        let object = resource.object.checked_sub(1).and_then(|index| self.objects.get(index as usize)).ok_or(Error::MalformedLx)?;
+        limits.check_bytes(resource.size as u64)?;
+        let start = resource.offset as usize;
+        let end = start.checked_add(resource.size as usize).ok_or(Error::MalformedLx)?;
+        if start == end {
+            return Ok(Vec::new());
+        }
+        let first_page = start / self.page_size;
+        let last_page = (end - 1) / self.page_size;
+        let mut data = vec![0; (last_page - first_page + 1) * self.page_size];
+        for (page, out) in (first_page..=last_page).zip(data.chunks_exact_mut(self.page_size)) {
+            if (page as u32) < object.page_count {
+                self.read_page(object.page_map.checked_add(page as u32).ok_or(Error::MalformedLx)?, out)?;
+            }
+        }
+        let skip = first_page * self.page_size;
+        data.truncate(end - skip);
+        data.drain(..start - skip);
+        Ok(data)
+    }
+}
@@ -0,0 +1,247 @@
+use std::convert::TryInto;
+
+use crate::{
+    cfb::{CompoundFile, DirEntry},
+    exelook::{Error, Limits, Result}
+};
+
+// Type bits from the _Columns table
+const MSITYPE_VALID: u16 = 0x0100;
+const MSITYPE_STRING: u16 = 0x0800;
+const MSITYPE_NULLABLE: u16 = 0x1000;
+const MSITYPE_KEY: u16 = 0x2000;
+// Table streams have this in front of their (compressed) name.
+const TABLE_PREFIX: char = '\u{4840}';
+
+// Strings borrow from the database's string pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value<'a> {
+    Null,
+    Int(i32),
+    Str(&'a str),
+    // The data is in a stream named after the table and the row's primary key
+    // values, joined with dots.
+    Stream
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    pub kind: u16
+}
+
+impl Column {
+    fn is_binary(&self) -> bool {
+        self.kind & !MSITYPE_NULLABLE == MSITYPE_STRING | MSITYPE_VALID
+    }
+    fn is_string(&self) -> bool {
+        self.kind & MSITYPE_STRING != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table<'a> {
+    pub columns: Vec<Column>,
+    pub rows: Vec<Vec<Value<'a>>>
+}
+
+impl<'a> Table<'a> {
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.name == name)
+    }
+}
+
+// An MSI database (.msi, .msm, .msp) on top of a compound file. Stream names are
+// compressed; they are decoded once up front and looked up by their plain name.
+pub struct Database<'a> {
+    file: &'a CompoundFile<'a>,
+    streams: Vec<(String, &'a DirEntry)>,
+    // Index 0 is the null string.
+    strings: Vec<String>,
+    long_refs: bool,
+    limits: Limits
+}
+
+// Two base-64 digits per UTF-16 unit in 0x3800..0x4800, one in 0x4800..0x4840.
+fn decode_stream_name(name: &[u16]) -> String {
+    let digit = |value: u16| match value {
+        0..=9 => (b'0' + value as u8) as char,
+        10..=35 => (b'A' + (value - 10) as u8) as char,
+        36..=61 => (b'a' + (value - 36) as u8) as char,
+        62 => '.',
+        _ => '_'
+    };
+    let mut decoded = String::new();
+    for c in std::char::decode_utf16(name.iter().cloned()).map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER)) {
+        match c as u32 {
+            value @ 0x3800..=0x47ff => {
+                decoded.push(digit((value - 0x3800) as u16 & 0x3f));
+                decoded.push(digit(((value - 0x3800) >> 6) as u16 & 0x3f));
+            },
+            value @ 0x4800..=0x483f => decoded.push(digit((value - 0x4800) as u16)),
+            _ => decoded.push(c)
+        }
+    }
+    decoded
+}
+
+// The string pool is a list of (length, refcount) pairs after a codepage header,
+// the characters are concatenated in _StringData. A string of 64K or more is
+// split over two pairs: an empty one holding the high word of the length in its
+// refcount, then the low word.
+fn read_strings(pool: &[u8], data: &[u8]) -> Result<(Vec<String>, bool)> {
+    let header = pool.get(..4).ok_or(Error::MalformedMsi)?;
+    let long_refs = header[3] & 0x80 != 0;
+    let codepage = u16::from_le_bytes(header[..2].try_into().unwrap());
+    let mut pairs = pool[4..].chunks_exact(4).map(|pair| {
+        (u16::from_le_bytes(pair[..2].try_into().unwrap()) as usize, u16::from_le_bytes(pair[2..].try_into().unwrap()) as usize)
+    });
+    let mut strings = vec![String::new()];
+    let mut offset = 0;
+    while let Some((len, refs)) = pairs.next() {
+        let len = match (len, refs) {
+            (0, 0) => 0,
+            (0, high) => high << 16 | pairs.next().ok_or(Error::MalformedMsi)?.0,
+            (len, _) => len
+        };
+        let end = offset + len;
+        let bytes = data.get(offset..end).ok_or(Error::MalformedMsi)?;
+        strings.push(match std::str::from_utf8(bytes) {
+            Ok(text) => text.to_string(),
+            // Other ANSI codepages; identifiers are ASCII either way.
+            Err(_) if codepage != 65001 => bytes.iter().map(|&c| c as char).collect(),
+            Err(_) => String::from_utf8_lossy(bytes).into_owned()
+        });
+        offset = end;
+    }
+    Ok((strings, long_refs))
+}
+
+impl<'a> Database<'a> {
+    pub fn open(file: &'a CompoundFile<'a>, limits: &Limits) -> Result<Database<'a>> {
+        let streams = file.children(0)?.into_iter().map(|entry| (decode_stream_name(&entry.name), entry)).collect();
+        let mut database = Database {file, streams, strings: Vec::new(), long_refs: false, limits: *limits};
+        let pool = database.table_stream("_StringPool")?.ok_or(Error::MalformedMsi)?;
+        let data = database.table_stream("_StringData")?.ok_or(Error::MalformedMsi)?;
+        let (strings, long_refs) = read_strings(&pool, &data)?;
+        database.strings = strings;
+        database.long_refs = long_refs;
+        Ok(database)
+    }
+
+    pub fn stream(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match self.streams.iter().find(|(stream, _)| stream == name) {
+            Some((_, entry)) => Ok(Some(self.file.read_stream(entry, &self.limits)?)),
+            None => Ok(None)
+        }
+    }
+
+    fn table_stream(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.stream(&format!("{}{}", TABLE_PREFIX, name))
+    }
+
+    fn string(&self, index: u32) -> Result<Value<'_>> {
+        match index {
+            0 => Ok(Value::Null),
+            index => Ok(Value::Str(self.strings.get(index as usize).ok_or(Error::MalformedMsi)?))
+        }
+    }
+
+    // Rows are stored column by column; integers are biased by 0x8000 or
+    // 0x80000000 so that 0 can mean null.
+    fn read_table(&self, data: &[u8], columns: &[Column]) -> Result<Vec<Vec<Value<'_>>>> {
+        let sizes = columns.iter().map(|column| match column.kind & 0xff {
+            _ if column.is_binary() => Ok(2),
+            _ if column.is_string() => Ok(if self.long_refs {3} else {2}),
+            1 | 2 => Ok(2),
+            4 => Ok(4),
+            _ => Err(Error::MalformedMsi)
+        }).collect::<Result<Vec<usize>>>()?;
+        let row_size: usize = sizes.iter().sum();
+        if row_size == 0 {
+            return Ok(Vec::new());
+        }
+        let count = data.len() / row_size;
+        let mut rows = vec![Vec::with_capacity(columns.len()); count];
+        let mut offset = 0;
+        for (column, &size) in columns.iter().zip(&sizes) {
+            for row in rows.iter_mut() {
+                let raw = &data[offset..offset + size];
+                let value = raw.iter().rev().fold(0u32, |value, &byte| value << 8 | byte as u32);
+                row.push(match size {
+                    _ if column.is_binary() => Value::Stream,
+                    _ if column.is_string() => self.string(value)?,
+                    _ if value == 0 => Value::Null,
+                    2 => Value::Int(value as i32 - 0x8000),
+                    _ => Value::Int((value ^ 0x8000_0000) as i32)
+                });
+                offset += size;
+            }
+        }
+        Ok(rows)
+    }
+
+    // The schema comes from the _Columns table, whose own layout is fixed:
+    // Table, Number, Name, Type. A missing table stream means no rows.
+    pub fn table(&self, name: &str) -> Result<Table<'_>> {
+        let schema = [
+            Column {name: "Table".to_string(), kind: MSITYPE_VALID | MSITYPE_STRING | MSITYPE_KEY | 64},
+            Column {name: "Number".to_string(), kind: MSITYPE_VALID | MSITYPE_KEY | 2},
+            Column {name: "Name".to_string(), kind: MSITYPE_VALID | MSITYPE_STRING | 64},
+            Column {name: "Type".to_string(), kind: MSITYPE_VALID | 2}
+        ];
+        let data = self.table_stream("_Columns")?.ok_or(Error::MalformedMsi)?;
+        let mut columns: Vec<(i32, Column)> = self.read_table(&data, &schema)?.into_iter().filter_map(|row| match &row[..] {
+            &[Value::Str(table), Value::Int(number), Value::Str(column), Value::Int(kind)] if table == name => {
+                Some((number, Column {name: column.to_string(), kind: kind as u16}))
+            },
+            _ => None
+        }).collect();
+        if columns.is_empty() {
+            return Err(Error::NoIconFound);
+        }
+        columns.sort_by_key(|&(number, _)| number);
+        let columns: Vec<Column> = columns.into_iter().map(|(_, column)| column).collect();
+        let rows = match self.table_stream(name)? {
+            Some(data) => self.read_table(&data, &columns)?,
+            None => Vec::new()
+        };
+        Ok(Table {columns, rows})
+    }
+
+    // ARPPRODUCTICON names a row of the Icon table, whose Data column holds a
+    // complete .ico or executable. Merge modules and packages without the
+    // property fall back to the first icon.
+    pub fn product_icon(&self) -> Result<Vec<u8>> {
+        let property = self.table("Property").ok();
+        let name = property.as_ref().and_then(|table| {
+            let (key, value) = (table.column("Property")?, table.column("Value")?);
+            table.rows.iter().find(|row| row[key] == Value::Str("ARPPRODUCTICON")).map(|row| row[value])
+        });
+        let icons = self.table("Icon")?;
+        let (key, data) = (icons.column("Name").ok_or(Error::MalformedMsi)?, icons.column("Data").ok_or(Error::MalformedMsi)?);
+        let row = match name {
+            Some(name) => icons.rows.iter().find(|row| row[key] == name),
+            None => icons.rows.first()
+        }.ok_or(Error::NoIconFound)?;
+        match (row[key], row[data]) {
+            (Value::Str(name), Value::Stream) => self.stream(&format!("Icon.{}", name))?.ok_or(Error::NoIconFound),
+            _ => Err(Error::MalformedMsi)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_stream_names() {
+        // "_StringPool" as a table and "Icon.app.exe" as a binary stream
+        let table = [0x4840, 0x3f3f, 0x4577, 0x446c, 0x3e6a, 0x44b2, 0x482f];
+        assert_eq!(decode_stream_name(&table), "\u{4840}_StringPool");
+        let stream = [0x4192, 0x4472, 0x413e, 0x44f3, 0x423e, 0x423b];
+        assert_eq!(decode_stream_name(&stream), "Icon.app.exe");
+        assert_eq!(decode_stream_name(&[0x0005, 0x0053]), "\u{5}S");
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{
+    bytes::u16_at,
+    exelook::{Diagnostic, Error, GroupId, Result}
+};
+
+pub const RT_ICON: u16 = 3;
+pub const RT_GROUP_ICON: u16 = 14;
+
+// Relative to the NE header.
+const RESOURCE_TABLE: usize = 0x24;
+const RESIDENT_NAMES: usize = 0x26;
+const TYPEINFO_SIZE: usize = 8;
+const NAMEINFO_SIZE: usize = 12;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NeResource<'a> {
+    pub kind: GroupId,
+    pub id: GroupId,
+    pub bytes: &'a [u8]
+}
+
+fn ne_header(bytes: &[u8]) -> Option<usize> {
+    if !bytes.starts_with(b"MZ") {
+        return None;
+    }
+    let offset = u16_at(bytes, 0x3c, Error::MalformedNe).ok()? as usize | (u16_at(bytes, 0x3e, Error::MalformedNe).ok()? as usize) << 16;
+    match bytes.get(offset..offset.checked_add(2)?) {
+        Some(b"NE") => Some(offset),
+        _ => None
+    }
+}
+
+pub fn is_ne(bytes: &[u8]) -> bool {
+    ne_header(bytes).is_some()
+}
+
+// Type and resource IDs with the high bit set are integers, anything else is
+// the offset of a length-prefixed string from the start of the resource table.
+fn resource_id(table: &[u8], value: u16) -> Result<GroupId> {
+    if value & 0x8000 != 0 {
+        return Ok(GroupId::Id((value & 0x7fff) as u32));
+    }
+    let offset = value as usize;
+    let len = *table.get(offset).ok_or(Error::MalformedNe)? as usize;
+    let name = table.get(offset + 1..offset + 1 + len).ok_or(Error::MalformedNe)?;
+    Ok(GroupId::Name(name.iter().map(|&c| c as char).collect()))
+}
+
+// Offsets and lengths in the resource table are in units of 1 << rscAlignShift.
+// Lengths are rounded up to that unit, so the last resource may claim a few
+// bytes past the end of the file. A resource starting past the end is left out
+// and reported with its own ID as the group.
+pub fn resources<'a>(bytes: &'a [u8], diagnostics: &mut Vec<Diagnostic>) -> Result<Vec<NeResource<'a>>> {
+    let header = ne_header(bytes).ok_or(Error::MalformedNe)?;
+    let table_start = header + u16_at(bytes, header + RESOURCE_TABLE, Error::MalformedNe)? as usize;
+    let table_end = header + u16_at(bytes, header + RESIDENT_NAMES, Error::MalformedNe)? as usize;
+    let table = bytes.get(table_start..table_end.max(table_start)).ok_or(Error::MalformedNe)?;
+    if table.is_empty() {
+        return Ok(Vec::new());
+    }
+    let shift = u16_at(table, 0, Error::MalformedNe)? as u32;
+    if shift > 24 {
+        return Err(Error::MalformedNe);
+    }
+    let mut resources = Vec::new();
+    let mut pos = 2;
+    loop {
+        let type_id = u16_at(table, pos, Error::MalformedNe)?;
+        if type_id == 0 {
+            break;
+        }
+        let kind = resource_id(table, type_id)?;
+        let count = u16_at(table, pos + 2, Error::MalformedNe)? as usize;
+        pos += TYPEINFO_SIZE;
+        for _ in 0..count {
+            let offset = (u16_at(table, pos, Error::MalformedNe)? as usize) << shift;
+            let length = (u16_at(table, pos + 2, Error::MalformedNe)? as usize) << shift;
+            let id = resource_id(table, u16_at(table, pos + 6, Error::MalformedNe)?)?;
+            pos += NAMEINFO_SIZE;
+            match bytes.get(offset..offset.saturating_add(length).min(bytes.len())) {
+                Some(data) => resources.push(NeResource {kind: kind.clone(), id, bytes: data}),
+                None => diagnostics.push(Diagnostic {group: Some(id), entry: None, error: Error::MalformedNe})
+            }
+        }
+    }
+    Ok(resources)
+}
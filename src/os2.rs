@@ -0,0 +1,253 @@
+use crate::{
+    bytes::{u16_at, u32_at},
+    dib::BitmapInfoHeader,
+    exelook::{Error, Limits, Result}
+};
+
+const BFT_BITMAPARRAY: &[u8] = b"BA";
+const BFT_ICON: &[u8] = b"IC";
+const BFT_POINTER: &[u8] = b"PT";
+const BFT_COLORICON: &[u8] = b"CI";
+const BFT_COLORPOINTER: &[u8] = b"CP";
+// usType, cbSize, xHotspot, yHotspot, offBits
+const FILE_HEADER_SIZE: usize = 14;
+// usType, cbSize, offNext, cxDisplay, cyDisplay
+const ARRAY_HEADER_SIZE: usize = 14;
+
+// An OS/2 icon or pointer rewritten as a Windows icon DIB, so it goes through
+// the same ranking and decoding as everything else.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Os2Image {
+    pub width: u32,
+    pub height: u32,
+    pub bit_count: u16,
+    pub hotspot: Option<(u16, u16)>,
+    pub dib: Vec<u8>
+}
+
+struct Bitmap<'a> {
+    kind: &'a [u8],
+    hotspot: (i16, i16),
+    header: BitmapInfoHeader<'a>,
+    // BITMAPINFOHEADER2 and palette
+    info: &'a [u8],
+    bits: usize
+}
+
+fn stride(width: usize, bit_count: usize) -> Result<usize> {
+    Ok(width.checked_mul(bit_count).and_then(|bits| bits.checked_add(31)).ok_or(Error::InvalidDimensions)? / 32 * 4)
+}
+
+// BITMAPFILEHEADER2 followed by its info header and palette; offBits is
+// relative to the start of the resource.
+fn bitmap(bytes: &[u8], offset: usize) -> Result<Bitmap<'_>> {
+    let info_start = offset.checked_add(FILE_HEADER_SIZE).ok_or(Error::MalformedOs2Bitmap)?;
+    let kind = bytes.get(offset..offset + 2).ok_or(Error::MalformedOs2Bitmap)?;
+    let hotspot = (u16_at(bytes, offset + 6, Error::MalformedOs2Bitmap)? as i16, u16_at(bytes, offset + 8, Error::MalformedOs2Bitmap)? as i16);
+    let bits = u32_at(bytes, offset + 10, Error::MalformedOs2Bitmap)? as usize;
+    let header = BitmapInfoHeader::from_bytes(bytes.get(info_start..).ok_or(Error::MalformedOs2Bitmap)?)?;
+    if header.compression() != 0 {
+        return Err(Error::UnknownCompression);
+    }
+    let entry_size = header.palette_entry_size();
+    let palette_size = match (header.colors_used() as usize, header.bit_count()) {
+        (0, bit_count) if bit_count <= 8 => Some(entry_size << bit_count),
+        (colors_used, _) => entry_size.checked_mul(colors_used)
+    }.ok_or(Error::InvalidDimensions)?;
+    let info_end = info_start.checked_add(header.header_size() + palette_size).ok_or(Error::MalformedOs2Bitmap)?;
+    let info = bytes.get(info_start..info_end).ok_or(Error::MalformedOs2Bitmap)?;
+    Ok(Bitmap {kind, hotspot, header, info, bits})
+}
+
+fn bits(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    bytes.get(offset..offset.checked_add(len).ok_or(Error::MalformedOs2Bitmap)?).ok_or(Error::MalformedOs2Bitmap)
+}
+
+// The 1bpp mask bitmap is twice the icon height with the XOR mask in the top
+// half and the AND mask in the bottom half, i.e. AND rows first in the
+// bottom-up data where Windows puts them last. Color variants add a second
+// bitmap for the image and only use the AND half of the mask.
+fn image(bytes: &[u8], offset: usize, limits: &Limits) -> Result<Os2Image> {
+    let mask = bitmap(bytes, offset)?;
+    let kind = mask.kind;
+    let color = match kind {
+        BFT_ICON | BFT_POINTER => None,
+        BFT_COLORICON | BFT_COLORPOINTER => Some(bitmap(bytes, offset + FILE_HEADER_SIZE + mask.info.len())?),
+        _ => return Err(Error::MalformedOs2Bitmap)
+    };
+    if mask.header.bit_count() != 1 || mask.header.width() <= 0 || mask.header.height() < 2 {
+        return Err(Error::MalformedOs2Bitmap);
+    }
+    let width = mask.header.width() as usize;
+    let height = mask.header.height() as usize / 2;
+    limits.check(width as u64, height as u64)?;
+    let mask_size = stride(width, 1)?.checked_mul(height).ok_or(Error::InvalidDimensions)?;
+    let and_mask = bits(bytes, mask.bits, mask_size)?;
+    let (info, xor_mask, bit_count) = match &color {
+        Some(color) => {
+            if color.header.width() as usize != width || color.header.height() as usize != height {
+                return Err(Error::MalformedOs2Bitmap);
+            }
+            let size = stride(width, color.header.bit_count() as usize)?.checked_mul(height).ok_or(Error::InvalidDimensions)?;
+            (color.info, bits(bytes, color.bits, size)?, color.header.bit_count())
+        },
+        None => (mask.info, bits(bytes, mask.bits.saturating_add(mask_size), mask_size)?, 1)
+    };
+    let mut dib = Vec::with_capacity(info.len() + xor_mask.len() + and_mask.len());
+    dib.extend_from_slice(info);
+    if let Some(color) = &color {
+        let doubled = height as u32 * 2;
+        if color.header.is_core() {
+            dib[6..8].copy_from_slice(&(doubled as u16).to_le_bytes());
+        } else {
+            dib[8..12].copy_from_slice(&doubled.to_le_bytes());
+        }
+    }
+    dib.extend_from_slice(xor_mask);
+    dib.extend_from_slice(and_mask);
+    // The hotspot is counted from the bottom left corner.
+    let hotspot = match kind {
+        BFT_POINTER | BFT_COLORPOINTER => {
+            let (x, y) = mask.hotspot;
+            let y = (height as i32 - 1 - y as i32).max(0);
+            Some((x.max(0) as u16, y as u16))
+        },
+        _ => None
+    };
+    Ok(Os2Image {width: width as u32, height: height as u32, bit_count, hotspot, dib})
+}
+
+// Either a single icon or pointer, or a BITMAPARRAYFILEHEADER2 chain with one
+// entry per device resolution. Broken entries are returned as errors in place.
+pub fn images(bytes: &[u8], limits: &Limits) -> Vec<Result<Os2Image>> {
+    if !bytes.starts_with(BFT_BITMAPARRAY) {
+        return vec![image(bytes, 0, limits)];
+    }
+    let mut images = Vec::new();
+    let mut offset = 0;
+    loop {
+        images.push(image(bytes, offset + ARRAY_HEADER_SIZE, limits));
+        // offNext only ever points forward; anything else would loop.
+        let next = u32_at(bytes, offset + 6, Error::MalformedOs2Bitmap).unwrap_or(0) as usize;
+        if next <= offset || bytes.get(next..next.saturating_add(2)) != Some(BFT_BITMAPARRAY) {
+            break;
+        }
+        offset = next;
+    }
+    images
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dib;
+
+    use super::*;
+
+    fn info_header(core: bool, width: u32, height: u32, bit_count: u16) -> Vec<u8> {
+        let mut header = Vec::new();
+        if core {
+            header.extend_from_slice(&12u32.to_le_bytes());
+            header.extend_from_slice(&(width as u16).to_le_bytes());
+            header.extend_from_slice(&(height as u16).to_le_bytes());
+        } else {
+            header.extend_from_slice(&64u32.to_le_bytes());
+            header.extend_from_slice(&width.to_le_bytes());
+            header.extend_from_slice(&height.to_le_bytes());
+        }
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&bit_count.to_le_bytes());
+        header.resize(if core {12} else {64}, 0);
+        header
+    }
+
+    // Black and the given RGB color, as BGR triples or quads.
+    fn palette(core: bool, rgb: [u8; 3]) -> Vec<u8> {
+        let mut palette = Vec::new();
+        for &[r, g, b] in &[[0, 0, 0], rgb] {
+            palette.extend_from_slice(&[b, g, r]);
+            if !core {
+                palette.push(0);
+            }
+        }
+        palette
+    }
+
+    fn file_header(kind: &[u8], hotspot: (i16, i16), bits: usize) -> Vec<u8> {
+        let mut header = kind.to_vec();
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&hotspot.0.to_le_bytes());
+        header.extend_from_slice(&hotspot.1.to_le_bytes());
+        header.extend_from_slice(&(bits as u32).to_le_bytes());
+        header
+    }
+
+    // A CI or CP of one solid color placed at base in the resource: the 1bpp mask
+    // bitmap (AND rows opaque, then XOR rows), then a 1bpp color bitmap using the
+    // second palette entry.
+    fn color_image(kind: &[u8], core: bool, size: u32, hotspot: (i16, i16), rgb: [u8; 3], base: usize) -> Vec<u8> {
+        let mask_info = [info_header(core, size, size * 2, 1), palette(core, [255, 255, 255])].concat();
+        let color_info = [info_header(core, size, size, 1), palette(core, rgb)].concat();
+        let mask_bits = base + 2 * FILE_HEADER_SIZE + mask_info.len() + color_info.len();
+        let row = stride(size as usize, 1).unwrap();
+        let color_bits = mask_bits + 2 * row * size as usize;
+        [
+            file_header(kind, hotspot, mask_bits), mask_info,
+            file_header(kind, hotspot, color_bits), color_info,
+            vec![0; 2 * row * size as usize], vec![0xff; row * size as usize]
+        ].concat()
+    }
+
+    fn decode(image: &Os2Image) -> Vec<u8> {
+        let (pixels, width, height) = dib::decode_dib(&image.dib, &Limits::default(), &mut Vec::new()).unwrap();
+        assert_eq!((width, height), (image.width, image.height));
+        pixels
+    }
+
+    #[test]
+    fn color_icons_with_either_header() {
+        for &core in &[true, false] {
+            let images = images(&color_image(BFT_COLORICON, core, 16, (0, 0), [255, 0, 0], 0), &Limits::default());
+            let image = images[0].as_ref().unwrap();
+            assert_eq!((image.width, image.height, image.bit_count, image.hotspot), (16, 16, 1, None));
+            let header = BitmapInfoHeader::from_bytes(&image.dib).unwrap();
+            assert_eq!((header.is_core(), header.height(), header.bit_count()), (core, 32, 1));
+            assert!(decode(image).chunks_exact(4).all(|pixel| pixel == [255, 0, 0, 255]), "core header: {}", core);
+        }
+    }
+
+    #[test]
+    fn pointer_hotspot_is_flipped() {
+        let images = images(&color_image(BFT_COLORPOINTER, false, 32, (5, 30), [0, 0, 255], 0), &Limits::default());
+        assert_eq!(images[0].as_ref().unwrap().hotspot, Some((5, 1)));
+    }
+
+    #[test]
+    fn bitmap_array_entries() {
+        let mut array = Vec::new();
+        let mut starts = Vec::new();
+        for i in 0..3 {
+            let start = array.len();
+            let base = start + ARRAY_HEADER_SIZE;
+            let entry = match i {
+                0 => color_image(BFT_COLORICON, true, 16, (0, 0), [255, 0, 0], base),
+                1 => b"CI broken".to_vec(),
+                _ => color_image(BFT_COLORICON, false, 32, (0, 0), [0, 255, 0], base)
+            };
+            let next = if i < 2 {base + entry.len()} else {0};
+            array.extend_from_slice(BFT_BITMAPARRAY);
+            array.extend_from_slice(&(ARRAY_HEADER_SIZE as u32).to_le_bytes());
+            array.extend_from_slice(&(next as u32).to_le_bytes());
+            array.extend_from_slice(&[0; 4]);
+            array.extend_from_slice(&entry);
+            starts.push(start);
+        }
+        let images = images(&array, &Limits::default());
+        assert_eq!(images.len(), 3);
+        assert_eq!(images[0].as_ref().unwrap().width, 16);
+        assert!(images[1].is_err());
+        assert!(decode(images[2].as_ref().unwrap()).chunks_exact(4).all(|pixel| pixel == [0, 255, 0, 255]));
+        // A chain pointing backwards ends the walk instead of looping.
+        array[starts[2] + 6..starts[2] + 10].copy_from_slice(&(starts[1] as u32).to_le_bytes());
+        assert_eq!(super::images(&array, &Limits::default()).len(), 3);
+    }
+}
@@ -0,0 +1,493 @@
+use std::convert::TryInto;
+
+use miniz_oxide::inflate::{
+    TINFLStatus,
+    core::{decompress, DecompressorOxide, inflate_flags}
+};
+
+use crate::exelook::{Result, Error, Limits};
+
+const SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a];
+
+const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4),
+    (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)
+];
+
+pub fn is_png(bytes: &[u8]) -> bool {
+    bytes.starts_with(&SIGNATURE)
+}
+
+pub struct PngHeader<'a> {
+    bytes: &'a [u8]
+}
+
+impl<'a> PngHeader<'a> {
+    pub fn from_bytes<'b>(bytes: &'b [u8]) -> Result<PngHeader<'b>> {
+        if bytes.len() < 24 || bytes[12..16] != [b'I', b'H', b'D', b'R'] {
+            Err(Error::MalformedPng)
+        } else {
+            Ok(PngHeader {bytes})
+        }
+    }
+    pub fn width(&self) -> i32 {
+        u32::from_be_bytes(self.bytes[16..20].try_into().unwrap()) as i32
+    }
+    pub fn height(&self) -> i32 {
+        u32::from_be_bytes(self.bytes[20..24].try_into().unwrap()) as i32
+    }
+    pub fn bit_depth(&self) -> u16 {
+        let channels = match self.bytes.get(25) {
+            Some(0) | Some(3) => 1,
+            Some(4) => 2,
+            Some(2) => 3,
+            _ => 4
+        };
+        self.bytes.get(24).map_or(8, |&depth| depth as u16) * channels
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {(crc >> 1) ^ 0xedb8_8320} else {crc >> 1};
+        }
+    }
+    !crc
+}
+
+struct Chunk<'a> {
+    kind: &'a [u8],
+    data: &'a [u8]
+}
+
+fn chunks(mut bytes: &[u8]) -> impl Iterator<Item = Result<Chunk<'_>>> {
+    std::iter::from_fn(move || {
+        if bytes.is_empty() {
+            return None;
+        }
+        if bytes.len() < 12 {
+            bytes = &[];
+            return Some(Err(Error::PngTruncated));
+        }
+        let len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+        let end = match len.checked_add(12) {
+            Some(end) if end <= bytes.len() => end,
+            _ => {
+                bytes = &[];
+                return Some(Err(Error::PngTruncated));
+            }
+        };
+        let crc = u32::from_be_bytes(bytes[end - 4..end].try_into().unwrap());
+        let chunk = Chunk {kind: &bytes[4..8], data: &bytes[8..end - 4]};
+        let valid = crc32(&bytes[4..end - 4]) == crc;
+        bytes = &bytes[end..];
+        Some(if valid {Ok(chunk)} else {Err(Error::PngChecksum)})
+    })
+}
+
+#[derive(Clone, Copy)]
+struct Format {
+    width: usize,
+    height: usize,
+    bit_depth: u8,
+    color_type: u8,
+    interlaced: bool
+}
+
+impl Format {
+    fn from_ihdr(data: &[u8]) -> Result<Format> {
+        if data.len() != 13 {
+            return Err(Error::MalformedPng);
+        }
+        let width = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+        let height = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+        let (bit_depth, color_type) = (data[8], data[9]);
+        let valid_depth = match color_type {
+            0 => [1, 2, 4, 8, 16].contains(&bit_depth),
+            3 => [1, 2, 4, 8].contains(&bit_depth),
+            2 | 4 | 6 => [8, 16].contains(&bit_depth),
+            _ => false
+        };
+        if !valid_depth || data[10] != 0 || data[11] != 0 || data[12] > 1 {
+            return Err(Error::UnsupportedPng);
+        }
+        if width == 0 || height == 0 {
+            return Err(Error::MalformedPng);
+        }
+        Ok(Format {width, height, bit_depth, color_type, interlaced: data[12] == 1})
+    }
+    fn channels(&self) -> usize {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1
+        }
+    }
+    fn bits_per_pixel(&self) -> usize {
+        self.channels() * self.bit_depth as usize
+    }
+    // usize::div_ceil needs Rust 1.73.
+    #[allow(clippy::manual_div_ceil)]
+    fn row_size(&self, width: usize) -> usize {
+        (width * self.bits_per_pixel() + 7) / 8
+    }
+    fn passes(&self) -> Vec<(usize, usize, usize, usize)> {
+        if self.interlaced {
+            ADAM7.to_vec()
+        } else {
+            vec![(0, 0, 1, 1)]
+        }
+    }
+    fn pass_size(&self, (x0, y0, dx, dy): (usize, usize, usize, usize)) -> (usize, usize) {
+        ((self.width + dx - 1 - x0) / dx, (self.height + dy - 1 - y0) / dy)
+    }
+    fn data_size(&self) -> usize {
+        self.passes().into_iter().map(|pass| {
+            let (width, height) = self.pass_size(pass);
+            if width == 0 {0} else {(self.row_size(width) + 1) * height}
+        }).sum()
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn unfilter(data: &[u8], row_size: usize, height: usize, bpp: usize) -> Result<Vec<u8>> {
+    let mut out = vec![0u8; row_size * height];
+    for y in 0..height {
+        let filtered = &data[y * (row_size + 1)..(y + 1) * (row_size + 1)];
+        let (prev, cur) = out.split_at_mut(y * row_size);
+        let prev = if y == 0 {None} else {Some(&prev[(y - 1) * row_size..])};
+        let cur = &mut cur[..row_size];
+        for x in 0..row_size {
+            let a = if x >= bpp {cur[x - bpp]} else {0};
+            let b = prev.map_or(0, |prev| prev[x]);
+            let c = if x >= bpp {prev.map_or(0, |prev| prev[x - bpp])} else {0};
+            let raw = filtered[x + 1];
+            cur[x] = match filtered[0] {
+                0 => raw,
+                1 => raw.wrapping_add(a),
+                2 => raw.wrapping_add(b),
+                3 => raw.wrapping_add(((a as u16 + b as u16) / 2) as u8),
+                4 => raw.wrapping_add(paeth(a, b, c)),
+                _ => return Err(Error::MalformedPng)
+            };
+        }
+    }
+    Ok(out)
+}
+
+struct Palette {
+    colors: Vec<[u8; 4]>,
+    transparent: Option<[u16; 3]>
+}
+
+impl Palette {
+    fn rgba(&self, format: &Format, row: &[u8], x: usize) -> [u8; 4] {
+        let depth = format.bit_depth as usize;
+        let sample = |i: usize| -> u16 {
+            match depth {
+                16 => u16::from_be_bytes([row[i * 2], row[i * 2 + 1]]),
+                8 => row[i] as u16,
+                _ => {
+                    let bit = i * depth;
+                    ((row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1) as u8) as u16
+                }
+            }
+        };
+        let scale = |value: u16| -> u8 {
+            match depth {
+                16 => (value >> 8) as u8,
+                8 => value as u8,
+                _ => (value as u32 * 255 / ((1 << depth) - 1)) as u8
+            }
+        };
+        let channels = format.channels();
+        match format.color_type {
+            0 => {
+                let gray = sample(x);
+                let alpha = if self.transparent == Some([gray, gray, gray]) {0} else {255};
+                let gray = scale(gray);
+                [gray, gray, gray, alpha]
+            },
+            2 => {
+                let rgb = [sample(x * 3), sample(x * 3 + 1), sample(x * 3 + 2)];
+                let alpha = if self.transparent == Some(rgb) {0} else {255};
+                [scale(rgb[0]), scale(rgb[1]), scale(rgb[2]), alpha]
+            },
+            3 => {
+                *self.colors.get(sample(x) as usize).unwrap_or(&[0, 0, 0, 0])
+            },
+            4 => {
+                let gray = scale(sample(x * channels));
+                [gray, gray, gray, scale(sample(x * channels + 1))]
+            },
+            _ => {
+                let base = x * channels;
+                [scale(sample(base)), scale(sample(base + 1)), scale(sample(base + 2)), scale(sample(base + 3))]
+            }
+        }
+    }
+}
+
+// the size of the filtered image data is known up front, so inflate straight into
+// a buffer of that size and treat anything that doesn't fill it exactly as corrupt
+fn inflate(data: &[u8], expected: usize) -> Result<Vec<u8>> {
+    let mut out = vec![0; expected];
+    let mut decompressor = Box::new(DecompressorOxide::new());
+    let flags = inflate_flags::TINFL_FLAG_PARSE_ZLIB_HEADER
+        | inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF
+        | inflate_flags::TINFL_FLAG_COMPUTE_ADLER32;
+    let (status, _, written) = decompress(&mut decompressor, data, &mut out, 0, flags);
+    match status {
+        TINFLStatus::Done if written == expected => Ok(out),
+        TINFLStatus::Done | TINFLStatus::FailedCannotMakeProgress | TINFLStatus::NeedsMoreInput => Err(Error::PngTruncated),
+        _ => Err(Error::MalformedPng)
+    }
+}
+
+pub fn decode_png(bytes: &[u8], limits: &Limits) -> Result<(Vec<u8>, u32, u32)> {
+    if !is_png(bytes) {
+        return Err(Error::MalformedPng);
+    }
+    let mut format = None;
+    let mut palette = Palette {colors: Vec::new(), transparent: None};
+    let mut idat = Vec::new();
+    let mut finished = false;
+    for chunk in chunks(&bytes[SIGNATURE.len()..]) {
+        let chunk = chunk?;
+        match (chunk.kind, format) {
+            (b"IHDR", None) => {
+                let header = Format::from_ihdr(chunk.data)?;
+                limits.check(header.width as u64, header.height as u64)?;
+                limits.check_bytes(header.data_size() as u64)?;
+                format = Some(header);
+            },
+            (_, None) => return Err(Error::MalformedPng),
+            (b"PLTE", Some(_)) => {
+                if chunk.data.len() % 3 != 0 || chunk.data.len() > 256 * 3 {
+                    return Err(Error::MalformedPng);
+                }
+                palette.colors = chunk.data.chunks_exact(3).map(|rgb| [rgb[0], rgb[1], rgb[2], 255]).collect();
+            },
+            (b"tRNS", Some(format)) => {
+                let data = chunk.data;
+                match format.color_type {
+                    3 => {
+                        for (color, &alpha) in palette.colors.iter_mut().zip(data) {
+                            color[3] = alpha;
+                        }
+                    },
+                    0 if data.len() == 2 => {
+                        let gray = u16::from_be_bytes([data[0], data[1]]);
+                        palette.transparent = Some([gray, gray, gray]);
+                    },
+                    2 if data.len() == 6 => {
+                        palette.transparent = Some([
+                            u16::from_be_bytes([data[0], data[1]]),
+                            u16::from_be_bytes([data[2], data[3]]),
+                            u16::from_be_bytes([data[4], data[5]])
+                        ]);
+                    },
+                    _ => return Err(Error::MalformedPng)
+                }
+            },
+            (b"IDAT", Some(_)) => {
+                idat.extend_from_slice(chunk.data);
+            },
+            (b"IEND", Some(_)) => {
+                finished = true;
+                break;
+            },
+            _ => {}
+        }
+    }
+    let format = format.ok_or(Error::PngTruncated)?;
+    if !finished || idat.is_empty() {
+        return Err(Error::PngTruncated);
+    }
+    if format.color_type == 3 && palette.colors.is_empty() {
+        return Err(Error::MalformedPng);
+    }
+    let raw = inflate(&idat, format.data_size())?;
+    #[allow(clippy::manual_div_ceil)]
+    let bpp = (format.bits_per_pixel() + 7) / 8;
+    let mut pixels = vec![0u8; format.width * format.height * 4];
+    let mut offset = 0;
+    for pass in format.passes() {
+        let (x0, y0, dx, dy) = pass;
+        let (width, height) = format.pass_size(pass);
+        if width == 0 || height == 0 {
+            continue;
+        }
+        let row_size = format.row_size(width);
+        let size = (row_size + 1) * height;
+        let rows = unfilter(&raw[offset..offset + size], row_size, height, bpp)?;
+        offset += size;
+        for (y, row) in rows.chunks_exact(row_size).enumerate() {
+            for x in 0..width {
+                let dst = ((y0 + y * dy) * format.width + x0 + x * dx) * 4;
+                pixels[dst..dst + 4].copy_from_slice(&palette.rgba(&format, row, x));
+            }
+        }
+    }
+    Ok((pixels, format.width as u32, format.height as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use miniz_oxide::deflate::compress_to_vec_zlib;
+
+    use super::*;
+
+    fn format(width: usize, height: usize, bit_depth: u8, color_type: u8, interlaced: bool) -> Format {
+        Format {width, height, bit_depth, color_type, interlaced}
+    }
+
+    // Unfiltered scanlines for every pass, packing the samples of each pixel at
+    // the format's bit depth.
+    fn image_data(format: &Format, samples: impl Fn(usize, usize) -> Vec<u16>) -> Vec<u8> {
+        let depth = format.bit_depth as usize;
+        let mut data = Vec::new();
+        for pass in format.passes() {
+            let (x0, y0, dx, dy) = pass;
+            let (width, height) = format.pass_size(pass);
+            if width == 0 {
+                continue;
+            }
+            for y in 0..height {
+                let mut row = vec![0u8; format.row_size(width)];
+                let mut bit = 0;
+                for x in 0..width {
+                    for sample in samples(x0 + x * dx, y0 + y * dy) {
+                        if depth == 16 {
+                            row[bit / 8..bit / 8 + 2].copy_from_slice(&sample.to_be_bytes());
+                        } else {
+                            row[bit / 8] |= (sample as u8) << (8 - depth - bit % 8);
+                        }
+                        bit += depth;
+                    }
+                }
+                data.push(0);
+                data.extend_from_slice(&row);
+            }
+        }
+        data
+    }
+
+    fn chunk(png: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = png.len();
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        let crc = crc32(&png[start..]);
+        png.extend_from_slice(&crc.to_be_bytes());
+    }
+
+    fn encode(format: &Format, extra: &[(&[u8], &[u8])], data: &[u8]) -> Vec<u8> {
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&(format.width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(format.height as u32).to_be_bytes());
+        ihdr.extend_from_slice(&[format.bit_depth, format.color_type, 0, 0, format.interlaced as u8]);
+        let mut png = SIGNATURE.to_vec();
+        chunk(&mut png, b"IHDR", &ihdr);
+        for (kind, data) in extra {
+            chunk(&mut png, kind, data);
+        }
+        chunk(&mut png, b"IDAT", &compress_to_vec_zlib(data, 6));
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    fn decode(png: &[u8]) -> Result<(Vec<u8>, u32, u32)> {
+        decode_png(png, &Limits::default())
+    }
+
+    fn gray_2bit() -> Vec<u8> {
+        let format = format(5, 3, 2, 0, false);
+        encode(&format, &[], &image_data(&format, |x, y| vec![((x + y) % 4) as u16]))
+    }
+
+    #[test]
+    fn adam7_16bit_rgba() {
+        let samples = |x: usize, y: usize| vec![(x as u16) << 12 | 0xff, (y as u16) << 12, 0x8000 | x as u16, 0xffff - (x * y) as u16 * 0x100];
+        let interlaced = format(11, 9, 16, 6, true);
+        let (pixels, width, height) = decode(&encode(&interlaced, &[], &image_data(&interlaced, samples))).unwrap();
+        assert_eq!((width, height), (11, 9));
+        for (i, pixel) in pixels.chunks_exact(4).enumerate() {
+            let expected: Vec<u8> = samples(i % 11, i / 11).into_iter().map(|sample| (sample >> 8) as u8).collect();
+            assert_eq!(pixel, &expected[..], "pixel {}", i);
+        }
+        let progressive = format(11, 9, 16, 6, false);
+        assert_eq!(decode(&encode(&progressive, &[], &image_data(&progressive, samples))).unwrap().0, pixels);
+    }
+
+    #[test]
+    fn gray_2bit_scales_to_8bit() {
+        let (pixels, width, height) = decode(&gray_2bit()).unwrap();
+        assert_eq!((width, height), (5, 3));
+        let grays: Vec<u8> = pixels.chunks_exact(4).map(|pixel| pixel[0]).collect();
+        assert_eq!(grays, [0, 85, 170, 255, 0, 85, 170, 255, 0, 85, 170, 255, 0, 85, 170]);
+        assert!(pixels.chunks_exact(4).all(|pixel| pixel[1] == pixel[0] && pixel[2] == pixel[0] && pixel[3] == 255));
+    }
+
+    #[test]
+    fn palette_with_partial_trns() {
+        let format = format(4, 2, 4, 3, false);
+        let palette = [255, 0, 0, 0, 255, 0, 0, 0, 255];
+        // Entries past the end of tRNS stay opaque.
+        let trns = [0x00, 0x80];
+        let png = encode(&format, &[(b"PLTE", &palette), (b"tRNS", &trns)], &image_data(&format, |x, _| vec![(x % 3) as u16]));
+        let (pixels, _, _) = decode(&png).unwrap();
+        assert_eq!(&pixels[..16], &[255, 0, 0, 0, 0, 255, 0, 0x80, 0, 0, 255, 255, 255, 0, 0, 0]);
+        let missing = encode(&format, &[], &image_data(&format, |_, _| vec![0]));
+        assert!(matches!(decode(&missing), Err(Error::MalformedPng)));
+    }
+
+    #[test]
+    fn bad_crc_is_rejected() {
+        let mut png = gray_2bit();
+        // The last byte of the IDAT data, just before its CRC and the IEND chunk.
+        let idat_end = png.len() - 12 - 4 - 1;
+        png[idat_end] ^= 0xff;
+        assert!(matches!(decode(&png), Err(Error::PngChecksum)));
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let png = gray_2bit();
+        assert!(matches!(decode(&png[..png.len() - 12]), Err(Error::PngTruncated)));
+        assert!(matches!(decode(&png[..png.len() - 20]), Err(Error::PngTruncated)));
+        assert!(matches!(decode(&png[..SIGNATURE.len()]), Err(Error::PngTruncated)));
+        // Valid chunks, but the compressed data holds one row less than the header
+        // promises.
+        let format = format(5, 3, 2, 0, false);
+        let short = encode(&format, &[], &image_data(&format, |x, _| vec![(x % 4) as u16])[..4]);
+        assert!(matches!(decode(&short), Err(Error::PngTruncated)));
+    }
+
+    #[test]
+    fn oversized_ihdr_exceeds_the_limits() {
+        // The IDAT is empty: the header alone has to be enough to reject these.
+        let huge = encode(&format(100_000, 100_000, 8, 6, false), &[], &[]);
+        assert!(matches!(decode(&huge), Err(Error::LimitExceeded)));
+        let wide = encode(&format(1 << 30, 1, 8, 6, false), &[], &[]);
+        assert!(matches!(decode(&wide), Err(Error::LimitExceeded)));
+        let small = Limits {max_bytes: 1024, ..Limits::default()};
+        let rgba = encode(&format(64, 64, 8, 6, false), &[], &[]);
+        assert!(matches!(decode_png(&rgba, &small), Err(Error::LimitExceeded)));
+    }
+}
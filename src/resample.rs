@@ -0,0 +1,179 @@
+use std::ops::Range;
+
+use crate::exelook::{Result, Limits};
+
+struct Contribution {
+    source: Range<usize>,
+    weights: Vec<f32>
+}
+
+// Downscaling averages every source pixel by how much of it the destination pixel
+// covers (box filter), upscaling just repeats source pixels to keep pixel art crisp.
+fn contributions(src: usize, dst: usize) -> Vec<Contribution> {
+    let scale = src as f64 / dst as f64;
+    (0..dst).map(|i| {
+        if dst > src {
+            let x = (((i as f64 + 0.5) * scale) as usize).min(src - 1);
+            return Contribution {source: x..x + 1, weights: vec![1.0]};
+        }
+        let start = i as f64 * scale;
+        let end = (i + 1) as f64 * scale;
+        let first = start.floor() as usize;
+        let last = (end.ceil() as usize).min(src);
+        let weights = (first..last).map(|x| {
+            let coverage = (end.min(x as f64 + 1.0) - start.max(x as f64)) / scale;
+            coverage as f32
+        }).collect();
+        Contribution {source: first..last, weights}
+    }).collect()
+}
+
+fn premultiply(pixels: &[u8]) -> Vec<f32> {
+    let mut out = Vec::with_capacity(pixels.len());
+    for px in pixels.chunks_exact(4) {
+        let alpha = px[3] as f32 / 255.0;
+        out.push(px[0] as f32 * alpha);
+        out.push(px[1] as f32 * alpha);
+        out.push(px[2] as f32 * alpha);
+        out.push(px[3] as f32);
+    }
+    out
+}
+
+fn unpremultiply(pixels: &[f32]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(pixels.len());
+    for px in pixels.chunks_exact(4) {
+        let alpha = px[3].clamp(0.0, 255.0);
+        let factor = if alpha > 0.0 {255.0 / alpha} else {0.0};
+        for &channel in &px[..3] {
+            out.push((channel * factor).round().clamp(0.0, 255.0) as u8);
+        }
+        out.push(alpha.round() as u8);
+    }
+    out
+}
+
+fn resample_rows(pixels: &[f32], width: usize, new_width: usize) -> Vec<f32> {
+    let contribs = contributions(width, new_width);
+    let mut out = Vec::with_capacity(pixels.len() / width * new_width);
+    for row in pixels.chunks_exact(width * 4) {
+        for contrib in &contribs {
+            let mut acc = [0.0f32; 4];
+            for (x, weight) in contrib.source.clone().zip(&contrib.weights) {
+                for c in 0..4 {
+                    acc[c] += row[x * 4 + c] * weight;
+                }
+            }
+            out.extend_from_slice(&acc);
+        }
+    }
+    out
+}
+
+fn resample_columns(pixels: &[f32], width: usize, new_height: usize) -> Vec<f32> {
+    let stride = width * 4;
+    let contribs = contributions(pixels.len() / stride, new_height);
+    let mut out = Vec::with_capacity(width * new_height * 4);
+    for contrib in &contribs {
+        let start = out.len();
+        out.resize(start + stride, 0.0);
+        for (y, weight) in contrib.source.clone().zip(&contrib.weights) {
+            let row = &pixels[y * stride..(y + 1) * stride];
+            for (dst, src) in out[start..].iter_mut().zip(row) {
+                *dst += src * weight;
+            }
+        }
+    }
+    out
+}
+
+pub fn resize(pixels: &[u8], width: u32, height: u32, stride: usize, new_width: u32, new_height: u32, limits: &Limits) -> Result<Vec<u8>> {
+    let (width, height) = (width as usize, height as usize);
+    let (new_width, new_height) = (new_width as usize, new_height as usize);
+    if width == 0 || height == 0 || new_width == 0 || new_height == 0 || stride < width * 4
+        || pixels.len() < stride * (height - 1) + width * 4 {
+        return Ok(vec![0; new_width * new_height * 4]);
+    }
+    let packed: Vec<u8> = (0..height).flat_map(|y| &pixels[y * stride..y * stride + width * 4]).cloned().collect();
+    if width == new_width && height == new_height {
+        return Ok(packed);
+    }
+    // The premultiplied source, the row pass and the column pass are all alive
+    // at once, at four f32 channels per pixel.
+    let floats = (width * height) as u64 + (new_width * height) as u64 + (new_width * new_height) as u64;
+    limits.check_bytes(floats.saturating_mul(16))?;
+    let premultiplied = premultiply(&packed);
+    let rows = resample_rows(&premultiplied, width, new_width);
+    let scaled = resample_columns(&rows, new_width, new_height);
+    Ok(unpremultiply(&scaled))
+}
+
+pub fn fit_within(width: u32, height: u32, size: u32) -> (u32, u32) {
+    if size == 0 || width == 0 || height == 0 {
+        (width, height)
+    } else if width >= height {
+        (size, ((height as u64 * size as u64 / width as u64) as u32).max(1))
+    } else {
+        (((width as u64 * size as u64 / height as u64) as u32).max(1), size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::exelook::Error;
+
+    use super::*;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+    #[test]
+    fn downscale_weights_cover_each_source_pixel() {
+        let halves = contributions(4, 2);
+        assert_eq!(halves[0].source, 0..2);
+        assert_eq!(halves[1].source, 2..4);
+        assert_eq!(halves[1].weights, vec![0.5, 0.5]);
+        // Three pixels into two: the middle one is split between both outputs.
+        let thirds = contributions(3, 2);
+        assert_eq!(thirds[0].source, 0..2);
+        assert_eq!(thirds[1].source, 1..3);
+        for contrib in &thirds {
+            assert!((contrib.weights.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        }
+        assert!((thirds[0].weights[0] - 2.0 / 3.0).abs() < 1e-6);
+        assert!((thirds[0].weights[1] - 1.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn upscale_repeats_source_pixels() {
+        let pixels = [RED, BLUE].concat();
+        let scaled = resize(&pixels, 2, 1, 8, 4, 2, &Limits::default()).unwrap();
+        let row = [RED, RED, BLUE, BLUE].concat();
+        assert_eq!(scaled, [&row[..], &row[..]].concat());
+    }
+
+    #[test]
+    fn transparent_pixels_do_not_bleed() {
+        assert_eq!(unpremultiply(&premultiply(&[10, 20, 30, 0])), vec![0, 0, 0, 0]);
+        assert_eq!(unpremultiply(&premultiply(&[10, 20, 30, 255])), vec![10, 20, 30, 255]);
+        // Averaging opaque red with transparent blue keeps the colour red.
+        let pixels = [RED, [0, 0, 255, 0]].concat();
+        assert_eq!(resize(&pixels, 2, 1, 8, 1, 1, &Limits::default()).unwrap(), vec![255, 0, 0, 128]);
+    }
+
+    #[test]
+    fn fit_within_keeps_aspect_ratio() {
+        assert_eq!(fit_within(32, 16, 16), (16, 8));
+        assert_eq!(fit_within(16, 32, 64), (32, 64));
+        assert_eq!(fit_within(1, 100, 10), (1, 10));
+        assert_eq!(fit_within(48, 48, 0), (48, 48));
+    }
+
+    #[test]
+    fn working_buffers_are_limited() {
+        let limits = Limits {max_bytes: 1024, ..Limits::default()};
+        let pixels = vec![255; 8 * 8 * 4];
+        assert!(matches!(resize(&pixels, 8, 8, 32, 4, 4, &limits), Err(Error::LimitExceeded)));
+        assert!(resize(&pixels, 8, 8, 32, 2, 2, &Limits::default()).is_ok());
+    }
+}
@@ -0,0 +1,232 @@
+use std::path::PathBuf;
+
+use exe_look::{ani, lnk, exelook_ani_bytes, exelook_bytes, exelook_bytes_index, exelook_lnk_bytes, exelook_lx_bytes, exelook_msi_bytes, exelook_ne_bytes, Error, GroupId, IconImage, Limits, Options};
+
+fn fixture_path(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", name].iter().collect()
+}
+
+fn fixture(name: &str) -> Vec<u8> {
+    std::fs::read(fixture_path(name)).unwrap()
+}
+
+fn size(size: u32) -> Options {
+    Options {size, ..Options::default()}
+}
+
+fn first_pixel(image: &IconImage) -> &[u8] {
+    pixel(image, 0, 0)
+}
+
+fn pixel(image: &IconImage, x: usize, y: usize) -> &[u8] {
+    &image.pixels[y * image.stride + x * 4..][..4]
+}
+
+fn dimensions(image: &IconImage) -> (u32, u32) {
+    (image.width, image.height)
+}
+
+// An animated cursor with three frames: a red and a green 16x16 cursor with
+// hotspots (1, 2) and (3, 4), and a frame that isn't an icon at all. The
+// sequence shows frames 1, 0, 2, 1 for 5, 6, 7 and 8 jiffies.
+#[test]
+fn ani_chunks() {
+    let file = fixture("cursor.ani");
+    let ani = ani::parse_ani(&file).unwrap();
+    assert_eq!((ani.header.frames, ani.header.steps, ani.header.display_rate), (3, 4, 10));
+    assert_eq!(ani.header.flags, ani::AF_ICON | ani::AF_SEQUENCE);
+    assert_eq!((ani.rates, ani.sequence), (vec![5, 6, 7, 8], vec![1, 0, 2, 1]));
+    assert_eq!(ani.frames.len(), 3);
+    assert_eq!(ani.frames[2], b"junk!");
+    assert!(matches!(ani::parse_ani(&file[..40]), Err(Error::MalformedAni)));
+}
+
+#[test]
+fn ani_frames_and_steps() {
+    let cursor = exelook_ani_bytes(&fixture("cursor.ani"), &size(16)).unwrap();
+    let hotspots: Vec<_> = cursor.frames.iter().map(|frame| frame.hotspot).collect();
+    assert_eq!(hotspots, [Some((1, 2)), Some((3, 4))]);
+    // The step showing the broken frame is dropped, the others keep their rate.
+    let steps: Vec<_> = cursor.steps.iter().map(|step| (step.frame, step.jiffies)).collect();
+    assert_eq!(steps, [(1, 5), (0, 6), (1, 8)]);
+    assert!(matches!(cursor.diagnostics[..], [ref diag] if diag.entry == Some(2) && matches!(diag.error, Error::MalformedIconFile)));
+}
+
+#[test]
+fn ani_thumbnail_is_the_first_step() {
+    let icon = exelook_bytes(&fixture("cursor.ani"), &size(16)).unwrap();
+    assert_eq!((icon.hotspot, first_pixel(&icon.image)), (Some((3, 4)), &[0, 255, 0, 255][..]));
+    // Only that frame was decoded, so the broken one went unnoticed.
+    assert!(icon.diagnostics.is_empty());
+    // Room for one frame but not for all of them
+    let options = Options {size: 16, limits: Limits {max_bytes: 16 * 16 * 4, ..Limits::default()}, ..Options::default()};
+    assert!(exelook_bytes(&fixture("cursor.ani"), &options).is_ok());
+    assert!(matches!(exelook_ani_bytes(&fixture("cursor.ani"), &options), Err(Error::LimitExceeded)));
+}
+
+#[test]
+fn ani_without_af_icon() {
+    let mut file = fixture("cursor.ani");
+    // The anih flags, 32 bytes into the chunk data at 0x2c
+    file[0x2c + 32] = ani::AF_SEQUENCE as u8;
+    // The frames are raw bitmaps then, which aren't supported.
+    assert_eq!(ani::parse_ani(&file).unwrap().frames.len(), 3);
+    assert!(matches!(exelook_ani_bytes(&file, &size(16)), Err(Error::UnsupportedAni)));
+    assert!(matches!(exelook_bytes(&file, &size(16)), Err(Error::UnsupportedAni)));
+}
+
+// A Windows 3.x executable with a MAINICON group (red 16x16, blue 32x32) and a
+// numbered group whose only image is missing.
+#[test]
+fn ne_main_icon() {
+    let ne = fixture("ne.exe");
+    let icon = exelook_ne_bytes(&ne, &size(32)).unwrap();
+    assert_eq!(icon.group, GroupId::Name("MAINICON".to_string()));
+    assert_eq!(first_pixel(&icon.image), [0, 0, 255, 255]);
+    let icon = exelook_ne_bytes(&ne, &size(16)).unwrap();
+    assert_eq!(first_pixel(&icon.image), [255, 0, 0, 255]);
+    assert!(icon.diagnostics.is_empty());
+    // Group 5 comes second and only lists an image that isn't there.
+    assert!(matches!(exelook_bytes_index(&ne, 1, &size(16)), Err(Error::NoIconFound)));
+    assert!(matches!(exelook_bytes_index(&ne, -5, &size(16)), Err(Error::NoIconFound)));
+    assert!(matches!(exelook_ne_bytes(&ne[..0x60], &size(16)), Err(Error::MalformedNe)));
+}
+
+#[test]
+fn ne_resource_past_the_end() {
+    let mut ne = fixture("ne.exe");
+    // The offset of the red 16x16 image, the first RT_ICON entry in the
+    // resource table at 0x80
+    ne[0xaa..0xac].copy_from_slice(&0xffffu16.to_le_bytes());
+    let icon = exelook_ne_bytes(&ne, &size(16)).unwrap();
+    assert_eq!((dimensions(&icon.image), first_pixel(&icon.image)), ((32, 32), &[0, 0, 255, 255][..]));
+    assert!(icon.diagnostics.iter().any(|diag| diag.group == Some(GroupId::Id(1)) && matches!(diag.error, Error::MalformedNe)));
+}
+
+// An OS/2 2.x executable with a bitmap, a broken pointer (id 2) and a bitmap
+// array pointer (id 5) holding a red 16x16 and a blue 32x32 color pointer, both
+// with a transparent top row. The first two pages are EXEPACK1 compressed.
+#[test]
+fn lx_pointer_array() {
+    let lx = fixture("lx.exe");
+    let icon = exelook_lx_bytes(&lx, &size(16)).unwrap();
+    assert_eq!((icon.group, icon.hotspot, dimensions(&icon.image)), (GroupId::Id(5), Some((3, 3)), (16, 16)));
+    assert_eq!(first_pixel(&icon.image)[3], 0);
+    assert_eq!(pixel(&icon.image, 0, 1), [255, 0, 0, 255]);
+    assert!(matches!(icon.diagnostics[..], [ref diag] if diag.group == Some(GroupId::Id(2)) && matches!(diag.error, Error::MalformedOs2Bitmap)));
+    let icon = exelook_lx_bytes(&lx, &size(32)).unwrap();
+    assert_eq!((icon.hotspot, dimensions(&icon.image)), (Some((5, 1)), (32, 32)));
+    assert_eq!(pixel(&icon.image, 31, 31), [0, 0, 255, 255]);
+}
+
+#[test]
+fn lx_page_map_overflow() {
+    let mut lx = fixture("lx.exe");
+    // The first object's page map index, in the object table at 0xc4 past the
+    // LX header at 0x80
+    for &index in &[u32::MAX, 0x2000_0000, 0x1000_0001] {
+        lx[0x80 + 0xc4 + 12..][..4].copy_from_slice(&index.to_le_bytes());
+        assert!(matches!(exelook_lx_bytes(&lx, &size(16)), Err(Error::MalformedLx)));
+    }
+}
+
+// A VxD holding a monochrome icon with a core header; its left half is
+// transparent, the rest white.
+#[test]
+fn le_mono_icon() {
+    let icon = exelook_lx_bytes(&fixture("le.vxd"), &size(32)).unwrap();
+    assert_eq!((icon.group, icon.hotspot, dimensions(&icon.image)), (GroupId::Id(7), None, (32, 32)));
+    assert_eq!(pixel(&icon.image, 15, 0)[3], 0);
+    assert_eq!(pixel(&icon.image, 16, 0), [255, 255, 255, 255]);
+}
+
+// An LX module without a DOS stub holding a single monochrome pointer.
+#[test]
+fn bare_lx_pointer() {
+    let icon = exelook_lx_bytes(&fixture("bare_lx.dll"), &size(32)).unwrap();
+    assert_eq!((icon.group, icon.hotspot, icon.bit_depth), (GroupId::Id(1), Some((10, 11)), 1));
+}
+
+// Packages whose Icon table holds prod.ico (an .ico with a 256 pixel green PNG)
+// and other.exe (red 16x16 and blue 32x32); ARPPRODUCTICON names the second row
+// in both.
+#[test]
+fn msi_product_icon() {
+    let icon = exelook_msi_bytes(&fixture("ico.msi"), &size(0)).unwrap();
+    assert_eq!((icon.group, dimensions(&icon.image)), (GroupId::Id(0), (256, 256)));
+    assert_eq!(first_pixel(&icon.image), [0, 255, 0, 255]);
+    let icon = exelook_msi_bytes(&fixture("exe.msi"), &size(32)).unwrap();
+    assert_eq!((icon.group, dimensions(&icon.image)), (GroupId::Id(1), (32, 32)));
+    assert_eq!(first_pixel(&icon.image), [0, 0, 255, 255]);
+}
+
+// Merge modules have no Property table; the first icon is used.
+#[test]
+fn msm_first_icon() {
+    let msm = fixture("exe.msm");
+    let icon = exelook_msi_bytes(&msm, &size(16)).unwrap();
+    assert_eq!(first_pixel(&icon.image), [255, 0, 0, 255]);
+    assert!(matches!(exelook_msi_bytes(&msm[..1024], &size(16)), Err(Error::MalformedCompoundFile)));
+}
+
+// An icon library with MAINICON (red 16x16, blue 32x32) and group 5 (green
+// 16x16).
+#[test]
+fn icl_by_index() {
+    let icl = fixture("icons.icl");
+    let green = |icon: exe_look::Icon| (icon.group, first_pixel(&icon.image).to_vec());
+    assert_eq!(green(exelook_bytes_index(&icl, 1, &size(16)).unwrap()), (GroupId::Id(5), vec![0, 255, 0, 255]));
+    assert_eq!(green(exelook_bytes_index(&icl, -5, &size(16)).unwrap()), (GroupId::Id(5), vec![0, 255, 0, 255]));
+    assert_eq!(exelook_bytes_index(&icl, 0, &size(32)).unwrap().group, GroupId::Name("MAINICON".to_string()));
+    assert!(matches!(exelook_bytes_index(&icl, 2, &size(16)), Err(Error::NoIconFound)));
+}
+
+// Resolves a link whose only usable candidate is windows_path, to the PE with
+// group 1.
+fn resolve_link(link: &str, windows_path: &str) -> exe_look::Icon {
+    exelook_lnk_bytes(&fixture(link), &size(32), |path| {
+        if path == windows_path {Some(fixture_path("simple.exe"))} else {None}
+    }).unwrap()
+}
+
+#[test]
+fn lnk_id_list_target() {
+    assert_eq!(resolve_link("idlist.lnk", r"C:\Program Files\multi.exe").group, GroupId::Id(1));
+}
+
+#[test]
+fn lnk_link_info_targets() {
+    assert_eq!(resolve_link("target.lnk", r"C:\apps\simple.exe").group, GroupId::Id(1));
+    assert_eq!(resolve_link("net.lnk", r"\\server\share\apps\simple.exe").group, GroupId::Id(1));
+}
+
+// The IconEnvironment block wins over the plain icon location and the target;
+// the link's icon index picks group 5 of the library.
+#[test]
+fn lnk_icon_environment() {
+    let icon = exelook_lnk_bytes(&fixture("icon_env.lnk"), &size(16), |path| match path {
+        r"%SystemRoot%\icons.icl" => Some(fixture_path("icons.icl")),
+        r"C:\Windows\icons.icl" | r"C:\apps\simple.exe" => Some(fixture_path("simple.exe")),
+        _ => None
+    }).unwrap();
+    assert_eq!(icon.group, GroupId::Id(5));
+    assert_eq!(first_pixel(&icon.image), [0, 255, 0, 255]);
+    // Unreachable candidates are skipped in order, each one reported.
+    let icon = exelook_lnk_bytes(&fixture("icon_env.lnk"), &size(16), |path| match path {
+        r"C:\apps\simple.exe" => Some(fixture_path("simple.exe")),
+        _ => Some(fixture_path("missing.icl"))
+    }).unwrap();
+    assert_eq!((icon.group, icon.diagnostics.len()), (GroupId::Id(1), 2));
+}
+
+// An IconEnvironment block too short for its paths is ignored; the link falls
+// back to the plain icon location.
+#[test]
+fn lnk_short_icon_environment() {
+    let mut bytes = fixture("icon_env.lnk");
+    let block = bytes.windows(4).position(|w| w == [7, 0, 0, 0xa0]).unwrap() - 4;
+    bytes[block..block + 4].copy_from_slice(&0x100u32.to_le_bytes());
+    let link = lnk::parse_lnk(&bytes).unwrap();
+    assert_eq!(link.icon_environment, None);
+    assert_eq!(link.icon_location.as_deref(), Some(r"C:\Windows\icons.icl"));
+}
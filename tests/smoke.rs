@@ -0,0 +1,40 @@
+use std::ffi::CString;
+
+use exe_look::{exelook, exelook_bytes, GroupId, IconImage, Options};
+
+// pelite reads headers in place and wants them aligned the way a file mapping
+// would be.
+#[repr(C, align(8))]
+struct Aligned<T: ?Sized>(T);
+
+// A PE with one icon group holding a red 16x16 and a blue 32x32 image.
+static SIMPLE: &Aligned<[u8]> = &Aligned(*include_bytes!("fixtures/simple.exe"));
+
+fn rgba(image: IconImage) -> (Vec<u8>, u32, u32) {
+    (image.pixels, image.width, image.height)
+}
+
+#[test]
+fn extracts_icon_from_pe() {
+    let icon = exelook_bytes(&SIMPLE.0, &Options {size: 32, ..Options::default()}).unwrap();
+    assert_eq!(icon.group, GroupId::Id(1));
+    assert_eq!(icon.bit_depth, 32);
+    assert!(icon.diagnostics.is_empty());
+    let (pixels, width, height) = rgba(icon.image);
+    assert_eq!((width, height), (32, 32));
+    assert_eq!(&pixels[..4], &[0, 0, 255, 255]);
+}
+
+#[test]
+fn extracts_icon_from_path() {
+    let path = CString::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/simple.exe")).unwrap();
+    let icon = exelook(&path, &Options {size: 16, ..Options::default()}).unwrap();
+    let (pixels, width, _) = rgba(icon.image);
+    assert_eq!(width, 16);
+    assert_eq!(&pixels[..4], &[255, 0, 0, 255]);
+}
+
+#[test]
+fn rejects_garbage() {
+    assert!(exelook_bytes(b"not an executable", &Options::default()).is_err());
+}